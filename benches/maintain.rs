@@ -0,0 +1,85 @@
+#[macro_use]
+extern crate criterion;
+extern crate specs;
+extern crate specs_hierarchy;
+
+use criterion::{BatchSize, Criterion};
+use specs::prelude::*;
+use specs_hierarchy::{Hierarchy, HierarchySystem};
+
+struct Parent {
+    entity: Entity,
+}
+
+impl Component for Parent {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+impl specs_hierarchy::Parent for Parent {
+    fn parent_entity(&self) -> Entity {
+        self.entity
+    }
+}
+
+const WIDTH: usize = 10_000;
+
+fn setup() -> (World, HierarchySystem<Parent>, Vec<Entity>, Vec<Entity>) {
+    let mut world = World::new();
+    world.register::<Parent>();
+    let system = HierarchySystem::<Parent>::new(&mut world);
+    let roots = (0..2)
+        .map(|_| world.create_entity().build())
+        .collect::<Vec<_>>();
+    let children = (0..WIDTH)
+        .map(|i| {
+            world
+                .create_entity()
+                .with(Parent {
+                    entity: roots[i % 2],
+                })
+                .build()
+        })
+        .collect::<Vec<_>>();
+    (world, system, roots, children)
+}
+
+fn wide_insert(c: &mut Criterion) {
+    c.bench_function("maintain wide insert", |b| {
+        b.iter_batched(
+            setup,
+            |(world, mut system, _, _)| {
+                system.run_now(&world);
+                world
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+fn wide_reparent(c: &mut Criterion) {
+    c.bench_function("maintain wide reparent", |b| {
+        b.iter_batched(
+            || {
+                let (mut world, mut system, roots, children) = setup();
+                system.run_now(&world);
+                world.maintain();
+                // swap the parent of every child, to be picked up by the next maintain
+                let mut parents = world.write_storage::<Parent>();
+                for (i, child) in children.iter().enumerate() {
+                    parents.get_mut(*child).unwrap().entity = roots[(i + 1) % 2];
+                }
+                drop(parents);
+                (world, system)
+            },
+            |(world, mut system)| {
+                system.run_now(&world);
+                assert_eq!(world.read_resource::<Hierarchy<Parent>>().len(), WIDTH + 2);
+                world
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, wide_insert, wide_reparent);
+criterion_main!(benches);
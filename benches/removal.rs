@@ -0,0 +1,69 @@
+#[macro_use]
+extern crate criterion;
+extern crate specs;
+extern crate specs_hierarchy;
+
+use criterion::{BatchSize, Criterion};
+use specs::prelude::*;
+use specs_hierarchy::{Hierarchy, HierarchySystem};
+
+struct Parent {
+    entity: Entity,
+}
+
+impl Component for Parent {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+impl specs_hierarchy::Parent for Parent {
+    fn parent_entity(&self) -> Entity {
+        self.entity
+    }
+}
+
+fn setup(lazy: bool) -> (World, HierarchySystem<Parent>, Vec<Entity>) {
+    let mut world = World::new();
+    world.register::<Parent>();
+    let mut system = HierarchySystem::<Parent>::new(&mut world);
+    world
+        .write_resource::<Hierarchy<Parent>>()
+        .set_lazy_removal(lazy);
+    let root = world.create_entity().build();
+    let children = (0..10_000)
+        .map(|_| world.create_entity().with(Parent { entity: root }).build())
+        .collect::<Vec<_>>();
+    system.run_now(&world);
+    world.maintain();
+    (world, system, children)
+}
+
+fn mass_removal(c: &mut Criterion, name: &str, lazy: bool) {
+    c.bench_function(name, |b| {
+        b.iter_batched(
+            || setup(lazy),
+            |(mut world, mut system, children)| {
+                // remove every other child, one frame at a time
+                for chunk in children.chunks(1000) {
+                    for entity in chunk.iter().step_by(2) {
+                        world.delete_entity(*entity).unwrap();
+                    }
+                    world.maintain();
+                    system.run_now(&world);
+                }
+                world
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+fn eager_removal(c: &mut Criterion) {
+    mass_removal(c, "eager removal", false);
+}
+
+fn lazy_removal(c: &mut Criterion) {
+    mass_removal(c, "lazy removal", true);
+}
+
+criterion_group!(benches, eager_removal, lazy_removal);
+criterion_main!(benches);
@@ -48,7 +48,7 @@ fn main() {
         parents.insert(e5, Parent { entity: e2 }).unwrap();
     }
 
-    dispatcher.dispatch(&mut world);
+    dispatcher.dispatch(&world);
 
     {
         let parents = world.read_storage::<Parent>();
@@ -56,7 +56,7 @@ fn main() {
             let formatted = parents
                 .get(*entity)
                 .map(|parent| format!("{:?}", parent.entity))
-                .unwrap_or(format!("None"));
+                .unwrap_or("None".to_string());
             println!("{:?}: {}", entity, formatted);
         }
     }
@@ -0,0 +1,176 @@
+//! Maps keyed by entity index, backed by a `Vec` instead of hashing.
+//!
+//! Entity indices are dense, so looking them up directly in a `Vec` is a lot cheaper than going
+//! through a `HashMap`, which matters in the hot loops of `Hierarchy::maintain`.
+
+use std::ops;
+
+use specs::prelude::Entity;
+use specs::world::Index;
+
+/// Map from entity `Index` to `V`, growing as needed.
+#[derive(Clone, Debug)]
+pub struct IndexMap<V> {
+    values: Vec<Option<V>>,
+    len: usize,
+}
+
+impl<V> IndexMap<V> {
+    pub fn new() -> Self {
+        IndexMap {
+            values: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, index: &Index) -> Option<&V> {
+        self.values
+            .get(*index as usize)
+            .and_then(|value| value.as_ref())
+    }
+
+    pub fn contains_key(&self, index: &Index) -> bool {
+        self.get(index).is_some()
+    }
+
+    pub fn insert(&mut self, index: Index, value: V) -> Option<V> {
+        let index = index as usize;
+        if index >= self.values.len() {
+            self.values.resize_with(index + 1, || None);
+        }
+        let old = self.values[index].replace(value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn remove(&mut self, index: &Index) -> Option<V> {
+        let old = self
+            .values
+            .get_mut(*index as usize)
+            .and_then(|value| value.take());
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.len = 0;
+    }
+
+    /// Get the number of indices the map can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.values.capacity()
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        let end = self
+            .values
+            .iter()
+            .rposition(|value| value.is_some())
+            .map(|index| index + 1)
+            .unwrap_or(0);
+        self.values.truncate(end);
+        self.values.shrink_to_fit();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Index, &V)> {
+        self.values
+            .iter()
+            .enumerate()
+            .filter_map(|(index, value)| value.as_ref().map(|value| (index as Index, value)))
+    }
+}
+
+impl<V> Default for IndexMap<V> {
+    fn default() -> Self {
+        IndexMap::new()
+    }
+}
+
+/// Map from `Entity` to `V`, stored by entity index.
+///
+/// Only one generation of an index can be in the map at a time, inserting another generation
+/// replaces the old entry.
+#[derive(Clone, Debug)]
+pub struct EntityMap<V> {
+    entries: IndexMap<(Entity, V)>,
+}
+
+impl<V> EntityMap<V> {
+    pub fn new() -> Self {
+        EntityMap {
+            entries: IndexMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&self, entity: &Entity) -> Option<&V> {
+        self.entries
+            .get(&entity.id())
+            .filter(|(key, _)| key == entity)
+            .map(|(_, value)| value)
+    }
+
+    pub fn contains_key(&self, entity: &Entity) -> bool {
+        self.get(entity).is_some()
+    }
+
+    pub fn insert(&mut self, entity: Entity, value: V) -> Option<V> {
+        self.entries
+            .insert(entity.id(), (entity, value))
+            .filter(|(key, _)| *key == entity)
+            .map(|(_, value)| value)
+    }
+
+    pub fn remove(&mut self, entity: &Entity) -> Option<V> {
+        if !self.contains_key(entity) {
+            return None;
+        }
+        self.entries.remove(&entity.id()).map(|(_, value)| value)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.capacity()
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.entries.shrink_to_fit();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Entity, &V)> {
+        self.entries.iter().map(|(_, (key, value))| (key, value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Entity> {
+        self.iter().map(|(key, _)| key)
+    }
+}
+
+impl<V> Default for EntityMap<V> {
+    fn default() -> Self {
+        EntityMap::new()
+    }
+}
+
+impl<V> ops::Index<&Entity> for EntityMap<V> {
+    type Output = V;
+
+    fn index(&self, entity: &Entity) -> &V {
+        self.get(entity).expect("entity not found in map")
+    }
+}
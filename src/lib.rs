@@ -5,7 +5,8 @@
 /// ## `Parent`
 ///
 /// This crate uses a generic parameter `P` for the parent component. Its bound by the `Parent`
-/// trait that only requires a getter for the `Entity` that's the parent.
+/// trait that only requires a getter for the `Entity` that's the parent. If you don't need a
+/// component of your own, `SimpleParent` can be used instead.
 ///
 /// ## Usage
 ///
@@ -42,20 +43,38 @@
 /// ```
 ///
 extern crate hibitset;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
 extern crate shrev;
 extern crate specs;
 
-use std::collections::{HashMap, HashSet};
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
 
 use hibitset::BitSetLike;
 use shrev::EventChannel;
 use specs::prelude::{
-    BitSet, Component, ComponentEvent, Entities, Entity, Join, ReadStorage, ReaderId, ResourceId,
-    System, SystemData, Tracked, World, WriteExpect, WriteStorage,
+    BitSet, Component, ComponentEvent, DenseVecStorage, Entities, Entity, FlaggedStorage, Join,
+    ReadStorage, ReaderId, ResourceId, System, SystemData, Tracked, World, WriteExpect,
+    WriteStorage,
 };
+use specs::storage::InsertResult;
 use specs::world::Index;
 
+use entity_map::{EntityMap, IndexMap};
+pub use multi::{MultiHierarchy, MultiHierarchySystem, MultiParent, MultiParentData};
+
+mod entity_map;
+mod multi;
+
 /// Hierarchy events.
 ///
 /// These are the events that are sent through the internal `EventChannel` in the `Hierarchy`
@@ -68,8 +87,93 @@ pub enum HierarchyEvent {
     /// component was removed from the component storage, just that the `Entity` will no longer be
     /// considered to be a part of the `Hierarchy`.
     Removed(Entity),
+    /// `Entity` had children before the last `maintain`, but lost all of them during it. The
+    /// `Entity` itself is still part of the `Hierarchy`, unless it is a root.
+    BecameLeaf(Entity),
+    /// `Entity` has a `Parent` component pointing at itself, which was ignored by the
+    /// `Hierarchy`.
+    Invalid(Entity),
+    /// `Entity` was moved from `old_parent` to `new_parent`. A `Modified` event is sent for the
+    /// `Entity` and its children as well.
+    Reparented {
+        /// The `Entity` that was moved
+        entity: Entity,
+        /// The parent the `Entity` had before the last `maintain`
+        old_parent: Entity,
+        /// The parent the `Entity` has now
+        new_parent: Entity,
+    },
+}
+
+impl HierarchyEvent {
+    /// Get the `Entity` this event is about.
+    fn entity(&self) -> Entity {
+        match *self {
+            HierarchyEvent::Modified(entity)
+            | HierarchyEvent::Removed(entity)
+            | HierarchyEvent::BecameLeaf(entity)
+            | HierarchyEvent::Invalid(entity)
+            | HierarchyEvent::Reparented { entity, .. } => entity,
+        }
+    }
+}
+
+/// Errors reported by `Hierarchy::try_maintain`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HierarchyError {
+    /// A parent link was ignored because it would create a cycle. Contains the entities that
+    /// would form the cycle, starting with the child whose link was ignored, followed by its
+    /// attempted parent and that parent's ancestors.
+    Cycle(Vec<Entity>),
+}
+
+impl fmt::Display for HierarchyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            HierarchyError::Cycle(ref entities) => {
+                write!(f, "parent links form a cycle: {:?}", entities)
+            }
+        }
+    }
+}
+
+impl Error for HierarchyError {}
+
+/// Errors reported by `reparent`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ReparentError {
+    /// The new parent is the entity itself or one of its recursive children.
+    WouldCycle,
+    /// The entity to reparent is no longer alive.
+    DeadEntity,
+}
+
+impl fmt::Display for ReparentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ReparentError::WouldCycle => write!(f, "new parent would create a cycle"),
+            ReparentError::DeadEntity => write!(f, "entity to reparent is not alive"),
+        }
+    }
+}
+
+impl Error for ReparentError {}
+
+/// Placement of a reparented `Entity` among the children of its new parent.
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub enum ReparentPlacement {
+    /// Place the `Entity` after all existing children of the new parent.
+    #[default]
+    End,
+    /// Place the `Entity` before all existing children of the new parent.
+    Front,
+    /// Place the `Entity` at the same position it had among the children of its old parent, or at
+    /// the end if the new parent has fewer children than that.
+    PreserveRank,
 }
 
+type ChildComparator = dyn Fn(&Entity, &Entity) -> Ordering + Send + Sync;
+
 /// Scene graph type hierarchy.
 ///
 /// Will use the given generic type `P` as the component type that provides parenting links. The
@@ -82,21 +186,55 @@ pub enum HierarchyEvent {
 /// completely different. When an `Entity` that is a parent gets removed from the hierarchy, the
 /// full tree of children below it will also be removed from the hierarchy.
 ///
-/// Any cycles in the hierarchy will cause Undefined Behavior.
+/// Parent links that would create a cycle in the hierarchy are ignored. `try_maintain` reports
+/// them as an error, while `maintain` panics on them, unless a handler was set with
+/// `set_cycle_handler`.
 pub struct Hierarchy<P> {
     sorted: Vec<Entity>,
-    entities: HashMap<Index, usize>,
+    tombstones: HashSet<Entity>,
+    entities: IndexMap<usize>,
     children: HashMap<Entity, Vec<Entity>>,
-    current_parent: HashMap<Entity, Entity>,
+    current_parent: EntityMap<Entity>,
     external_parents: HashSet<Entity>,
+    depths: HashMap<Entity, usize>,
+    old_depths: HashMap<Entity, usize>,
+    depth_changed: Vec<Entity>,
+    descendant_counts: HashMap<Entity, usize>,
+    moved: Vec<Entity>,
+    frame_delta: FrameDelta,
+    emptied: Vec<Entity>,
+    references: HashMap<Entity, Vec<Entity>>,
+    collapsed: HashSet<Entity>,
     changed: EventChannel<HierarchyEvent>,
 
     reader_id: ReaderId<ComponentEvent>,
     modified: BitSet,
     inserted: BitSet,
     removed: BitSet,
+    dirty: BitSet,
+    relinked: HashSet<Entity>,
 
     scratch_set: HashSet<Entity>,
+    sorted_before: Vec<Entity>,
+    index_before: IndexMap<usize>,
+    spare_children: Vec<Vec<Entity>>,
+    reparent_placement: ReparentPlacement,
+    cycle_handler: Option<Box<dyn FnMut(Entity, Entity) + Send + Sync>>,
+    cycles: Vec<Vec<Entity>>,
+    deferred: Vec<(Entity, Entity)>,
+    cyclic: HashSet<(Entity, Entity)>,
+    auto_sorted: HashSet<Entity>,
+    child_comparator: Option<Box<ChildComparator>>,
+    lazy_removal: bool,
+    tick: u64,
+    history: VecDeque<(u64, HierarchyEvent)>,
+    history_capacity: usize,
+    batches: HashMap<Entity, Batch>,
+    rejected: HashSet<Entity>,
+    detaching: HashSet<Entity>,
+    coalesce_events: bool,
+    coalescing: bool,
+    coalesced: BitSet,
 
     _phantom: PhantomData<P>,
 }
@@ -110,23 +248,118 @@ impl<P> Hierarchy<P> {
     {
         Hierarchy {
             sorted: Vec::new(),
-            entities: HashMap::new(),
-            current_parent: HashMap::new(),
+            tombstones: HashSet::new(),
+            entities: IndexMap::new(),
+            current_parent: EntityMap::new(),
             external_parents: HashSet::new(),
             children: HashMap::new(),
+            depths: HashMap::new(),
+            old_depths: HashMap::new(),
+            depth_changed: Vec::new(),
+            descendant_counts: HashMap::new(),
+            moved: Vec::new(),
+            frame_delta: FrameDelta::default(),
+            emptied: Vec::new(),
+            references: HashMap::new(),
+            collapsed: HashSet::new(),
             changed: EventChannel::new(),
 
             reader_id,
             modified: BitSet::new(),
             inserted: BitSet::new(),
             removed: BitSet::new(),
+            dirty: BitSet::new(),
+            relinked: HashSet::new(),
 
             scratch_set: HashSet::default(),
+            sorted_before: Vec::new(),
+            index_before: IndexMap::new(),
+            spare_children: Vec::new(),
+            reparent_placement: ReparentPlacement::default(),
+            cycle_handler: None,
+            cycles: Vec::new(),
+            deferred: Vec::new(),
+            cyclic: HashSet::new(),
+            auto_sorted: HashSet::new(),
+            child_comparator: None,
+            lazy_removal: false,
+            tick: 0,
+            history: VecDeque::new(),
+            history_capacity: 0,
+            batches: HashMap::new(),
+            rejected: HashSet::new(),
+            detaching: HashSet::new(),
+            coalesce_events: false,
+            coalescing: false,
+            coalesced: BitSet::new(),
 
             _phantom: PhantomData,
         }
     }
 
+    /// Rebuild a hierarchy from a snapshot taken with `snapshot`, for example after loading a
+    /// saved game.
+    ///
+    /// The snapshot stores entities by their index and generation, and `remap` is called with
+    /// these to get the matching `Entity` in the current world. Returning `None` drops the entity
+    /// from the hierarchy, together with its subtree, which can be used to handle entities whose
+    /// generation no longer matches.
+    ///
+    /// No events are sent for the restored entities, and `reader_id` should be registered on the
+    /// `Parent` storage after the components were restored.
+    pub fn from_snapshot<F>(
+        reader_id: ReaderId<ComponentEvent>,
+        snapshot: &HierarchySnapshot,
+        mut remap: F,
+    ) -> Self
+    where
+        P: Component,
+        P::Storage: Tracked,
+        F: FnMut(Index, i32) -> Option<Entity>,
+    {
+        let mut hierarchy = Hierarchy::new(reader_id);
+        let mut mapped = HashMap::new();
+        let mut map = |entity: SnapshotEntity| {
+            *mapped
+                .entry(entity)
+                .or_insert_with(|| remap(entity.id, entity.gen))
+        };
+        let children = snapshot
+            .children
+            .iter()
+            .map(|(parent, children)| (*parent, children.as_slice()))
+            .collect::<HashMap<_, _>>();
+        let linked = snapshot
+            .parents
+            .iter()
+            .map(|(child, _)| *child)
+            .collect::<HashSet<_>>();
+
+        // walk down from the roots, so parents are added before their children
+        let mut queue = VecDeque::new();
+        for (parent, _) in &snapshot.children {
+            if !linked.contains(parent) {
+                if let Some(entity) = map(*parent) {
+                    queue.push_back((*parent, entity));
+                }
+            }
+        }
+        while let Some((parent, parent_entity)) = queue.pop_front() {
+            for child in children.get(&parent).cloned().unwrap_or(&[]) {
+                let entity = match map(*child) {
+                    Some(entity) => entity,
+                    None => continue,
+                };
+                if entity == parent_entity || hierarchy.entities.contains_key(&entity.id()) {
+                    continue;
+                }
+                hierarchy.push_leaf(entity, parent_entity);
+                queue.push_back((*child, entity));
+            }
+        }
+        hierarchy
+    }
+
     /// Get all entities that contain parents, in sorted order, where parents are guaranteed to
     /// be before their children.
     ///
@@ -135,6 +368,97 @@ impl<P> Hierarchy<P> {
         self.sorted.as_slice()
     }
 
+    /// Get the number of entities in the hierarchy, including the roots of all trees.
+    pub fn len(&self) -> usize {
+        self.sorted.len() - self.tombstones.len() + self.tree_count()
+    }
+
+    /// Returns `true` if the hierarchy contains no entities.
+    pub fn is_empty(&self) -> bool {
+        self.sorted.len() == self.tombstones.len()
+    }
+
+    /// Get the root of every tree in the hierarchy, in the order their first child appears in
+    /// `all`.
+    ///
+    /// Roots are the entities that are parents, but have no parent themselves. An entity that
+    /// lost all of its children is no longer a root.
+    pub fn roots(&self) -> Vec<Entity> {
+        let mut roots = Vec::new();
+        let mut visited = HashSet::new();
+        for entity in self.first_layer() {
+            let root = self.current_parent[&entity];
+            if visited.insert(root) {
+                roots.push(root);
+            }
+        }
+        roots
+    }
+
+    /// Get the number of separate trees in the hierarchy.
+    pub fn tree_count(&self) -> usize {
+        self.external_parents
+            .iter()
+            .filter(|entity| !self.children(**entity).is_empty())
+            .count()
+    }
+
+    /// Returns `true` if the hierarchy consists of exactly one tree, see `tree_count`.
+    pub fn is_single_tree(&self) -> bool {
+        self.tree_count() == 1
+    }
+
+    /// Get the number of parent-child links in the hierarchy.
+    pub fn edge_count(&self) -> usize {
+        self.current_parent.len()
+    }
+
+    /// Get the largest number of direct children of any single parent, or `0` if the hierarchy is
+    /// empty.
+    pub fn max_branching_factor(&self) -> usize {
+        self.children
+            .values()
+            .map(|children| children.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns an iterator over all entities that contain parents in sorted order, together with
+    /// their immediate children.
+    pub fn iter_with_children(&self) -> impl Iterator<Item = (Entity, &[Entity])> + '_ {
+        self.iter_sorted()
+            .map(move |entity| (entity, self.children(entity)))
+    }
+
+    /// Returns an iterator over every parent in the hierarchy that has children, together with
+    /// its children.
+    ///
+    /// The roots come first, in the order of `roots`, followed by the other parents in sorted
+    /// order.
+    pub fn sibling_groups(&self) -> impl Iterator<Item = (Entity, &[Entity])> + '_ {
+        self.roots()
+            .into_iter()
+            .chain(self.iter_sorted())
+            .map(move |entity| (entity, self.children(entity)))
+            .filter(|(_, children)| !children.is_empty())
+    }
+
+    /// Get every unordered pair of entities that share a parent, each pair once.
+    ///
+    /// The pairs are grouped by parent in the order of `sibling_groups`, and within a group the
+    /// first entity of a pair is always before the second one in `children` order.
+    pub fn sibling_pairs(&self) -> Vec<(Entity, Entity)> {
+        let mut pairs = Vec::new();
+        for (_, children) in self.sibling_groups() {
+            for (i, a) in children.iter().enumerate() {
+                for b in &children[i + 1..] {
+                    pairs.push((*a, *b));
+                }
+            }
+        }
+        pairs
+    }
+
     /// Get the immediate children of a specific entity.
     pub fn children(&self, entity: Entity) -> &[Entity] {
         self.children
@@ -143,6 +467,85 @@ impl<P> Hierarchy<P> {
             .unwrap_or(&[])
     }
 
+    /// Returns an iterator over the immediate children of a specific entity, in reverse order.
+    pub fn children_rev(&self, entity: Entity) -> impl Iterator<Item = Entity> + '_ {
+        self.children(entity).iter().rev().cloned()
+    }
+
+    /// Get the immediate children of a specific entity, in sorted order.
+    ///
+    /// The order of `children` is the order the children were added in, which can differ from
+    /// the sorted order after reparenting.
+    pub fn children_sorted(&self, entity: Entity) -> Vec<Entity> {
+        let mut children = self.children(entity).to_vec();
+        children.sort_by_key(|child| self.index_of(*child));
+        children
+    }
+
+    /// Get the children of every parent flattened into a single `Vec`, together with the
+    /// `(offset, count)` of each parent's children within it.
+    ///
+    /// The children of each parent are contiguous and in the same order as `children`, but the
+    /// order of the parents themselves is unspecified.
+    pub fn flat_children(&self) -> (Vec<Entity>, HashMap<Entity, (usize, usize)>) {
+        let mut flat = Vec::with_capacity(self.current_parent.len());
+        let mut ranges = HashMap::with_capacity(self.children.len());
+        for (parent, children) in &self.children {
+            ranges.insert(*parent, (flat.len(), children.len()));
+            flat.extend_from_slice(children);
+        }
+        (flat, ranges)
+    }
+
+    /// Get the order of the children of every parent, for restoring it later with
+    /// `import_order`.
+    ///
+    /// The order of the parents themselves is unspecified.
+    pub fn export_order(&self) -> Vec<(Entity, Vec<Entity>)> {
+        self.children
+            .iter()
+            .filter(|(_, children)| children.len() > 1)
+            .map(|(parent, children)| (*parent, children.clone()))
+            .collect()
+    }
+
+    /// Restore the order of children previously captured with `export_order`.
+    ///
+    /// The structure itself is not changed: entities that are no longer children of the given
+    /// parent are ignored, and children missing from the given order keep their relative order
+    /// after the ordered ones.
+    pub fn import_order(&mut self, order: &[(Entity, Vec<Entity>)]) {
+        for (parent, ordered) in order {
+            if let Some(children) = self.children.get_mut(parent) {
+                let rank = ordered
+                    .iter()
+                    .enumerate()
+                    .map(|(rank, entity)| (*entity, rank))
+                    .collect::<HashMap<_, _>>();
+                children.sort_by_key(|entity| rank.get(entity).cloned().unwrap_or(usize::MAX));
+            }
+        }
+    }
+
+    /// Get the direct children of `parent` for which `keep` returns `true`, sorted by `key`.
+    ///
+    /// The sort is stable, so children with equal keys keep their order from `children`.
+    pub fn children_ordered_by<K, F, G>(&self, parent: Entity, keep: F, key: G) -> Vec<Entity>
+    where
+        K: Ord,
+        F: Fn(Entity) -> bool,
+        G: Fn(Entity) -> K,
+    {
+        let mut children = self
+            .children(parent)
+            .iter()
+            .cloned()
+            .filter(|child| keep(*child))
+            .collect::<Vec<_>>();
+        children.sort_by_key(|child| key(*child));
+        children
+    }
+
     /// Get all children of this entity recursively as a `BitSet`
     ///
     /// This does not include the parent entity you pass in.
@@ -152,6 +555,84 @@ impl<P> Hierarchy<P> {
         entities
     }
 
+    /// Get the whole subtree of this entity as a `BitSet`.
+    ///
+    /// Unlike `all_children`, this includes the root entity you pass in.
+    pub fn subtree_set(&self, root: Entity) -> BitSet {
+        let mut entities = self.all_children(root);
+        entities.add(root.id());
+        entities
+    }
+
+    /// Get all entities in the hierarchy that are neither this entity, nor one of its ancestors or
+    /// descendants, as a `BitSet`.
+    ///
+    /// This includes the roots of other trees.
+    pub fn unrelated(&self, entity: Entity) -> BitSet {
+        let mut entities = BitSet::new();
+        for other in self.roots().into_iter().chain(self.iter_sorted()) {
+            entities.add(other.id());
+        }
+        for related in (&self.ancestor_set(entity) | &self.subtree_set(entity)).iter() {
+            entities.remove(related);
+        }
+        entities
+    }
+
+    /// Get all ancestors of this entity as a `BitSet`, up to and including the root of its tree.
+    ///
+    /// This does not include the entity you pass in.
+    pub fn ancestor_set(&self, entity: Entity) -> BitSet {
+        let mut entities = BitSet::new();
+        let mut current = entity;
+        while let Some(parent) = self.current_parent.get(&current) {
+            entities.add(parent.id());
+            current = *parent;
+        }
+        entities
+    }
+
+    /// Get the ancestors of all given entities as a single `BitSet`, see `ancestor_set`.
+    ///
+    /// The given entities themselves are only included if they are an ancestor of another given
+    /// entity.
+    pub fn ancestors_of_set<I>(&self, entities: I) -> BitSet
+    where
+        I: IntoIterator<Item = Entity>,
+    {
+        let mut ancestors = BitSet::new();
+        for entity in entities {
+            let mut current = entity;
+            while let Some(parent) = self.current_parent.get(&current) {
+                // the rest of the chain was already added by an earlier entity
+                if ancestors.add(parent.id()) {
+                    break;
+                }
+                current = *parent;
+            }
+        }
+        ancestors
+    }
+
+    /// Get every entity that would be removed if all given entities were deleted, as a single
+    /// `BitSet`.
+    ///
+    /// This is the union of the `subtree_set` of each given entity, so the given entities are
+    /// included.
+    pub fn deletion_closure<I>(&self, entities: I) -> BitSet
+    where
+        I: IntoIterator<Item = Entity>,
+    {
+        let mut closure = BitSet::new();
+        for entity in entities {
+            // the subtree was already added by an ancestor
+            if !closure.add(entity.id()) {
+                self.add_children_to_set(entity, &mut closure);
+            }
+        }
+        closure
+    }
+
     fn add_children_to_set(&self, entity: Entity, set: &mut BitSet) {
         if let Some(children) = self.children.get(&entity) {
             for child in children {
@@ -169,527 +650,6915 @@ impl<P> Hierarchy<P> {
         SubHierarchyIterator::new(self, entity)
     }
 
-    /// Get the parent of a specific entity
-    pub fn parent(&self, entity: Entity) -> Option<Entity> {
-        self.current_parent.get(&entity).cloned()
+    /// Returns an iterator over all of the recursive children of this entity, in breadth-first
+    /// order.
+    ///
+    /// This does not include the parent entity you pass in. All children at one depth are
+    /// returned before any child at the next depth, and siblings are in `children` order.
+    pub fn bfs_iter(&self, root: Entity) -> BfsIterator<'_, P> {
+        BfsIterator::new(self, root)
     }
 
-    /// Get a token for tracking the modification events from the hierarchy
-    pub fn track(&mut self) -> ReaderId<HierarchyEvent> {
-        self.changed.register_reader()
+    /// Returns an iterator over all of the recursive children of this entity, in depth-first
+    /// pre-order.
+    ///
+    /// This does not include the parent entity you pass in. Each entity is returned before its
+    /// children, and the whole subtree of an entity is returned before its next sibling. Siblings
+    /// are in `children` order.
+    pub fn dfs_preorder_iter(&self, root: Entity) -> DfsPreorderIterator<'_, P> {
+        DfsPreorderIterator::new(self, root)
     }
 
-    /// Get the `EventChannel` for the modification events for reading
-    pub fn changed(&self) -> &EventChannel<HierarchyEvent> {
-        &self.changed
+    /// Returns an iterator over all of the recursive children of this entity, each together with
+    /// the path of entities from `root` down to it, including both.
+    ///
+    /// The children are in the order of `dfs_preorder_iter`, which allows the path to be kept up
+    /// to date while walking the subtree once.
+    pub fn descendants_with_path(
+        &self,
+        root: Entity,
+    ) -> impl Iterator<Item = (Entity, Vec<Entity>)> + '_ {
+        let mut path = vec![root];
+        self.dfs_preorder_iter(root).map(move |entity| {
+            let parent = self.current_parent[&entity];
+            while path.last() != Some(&parent) {
+                path.pop();
+            }
+            path.push(entity);
+            (entity, path.clone())
+        })
     }
 
-    /// Maintain the hierarchy, usually only called by `HierarchySystem`.
-    pub fn maintain(&mut self, data: ParentData<P>)
-    where
-        P: Component + Parent,
-        P::Storage: Tracked,
-    {
-        let ParentData {
-            entities, parents, ..
-        } = data;
+    /// Returns an iterator over all of the recursive children of this entity, in depth-first
+    /// post-order.
+    ///
+    /// This does not include the parent entity you pass in. Each entity is returned after all of
+    /// its children, and the whole subtree of an entity is returned before its next sibling.
+    /// Siblings are in `children` order.
+    pub fn dfs_postorder_iter(&self, root: Entity) -> DfsPostorderIterator<'_, P> {
+        DfsPostorderIterator::new(self, root)
+    }
 
-        // Maintain tracking
-        self.modified.clear();
-        self.inserted.clear();
-        self.removed.clear();
+    /// Returns an iterator over all of the recursive children of this entity, in reverse sorted
+    /// order.
+    ///
+    /// This does not include the parent entity you pass in. Children are guaranteed to be prior
+    /// to their parents.
+    pub fn all_children_iter_rev(&self, entity: Entity) -> impl Iterator<Item = Entity> {
+        let descendants = self.all_children_iter(entity).collect::<Vec<_>>();
+        descendants.into_iter().rev()
+    }
 
-        let events = parents.channel().read(&mut self.reader_id);
-        for event in events {
-            match event {
-                ComponentEvent::Modified(id) => {
-                    self.modified.add(*id);
-                }
-                ComponentEvent::Inserted(id) => {
-                    self.inserted.add(*id);
-                }
-                ComponentEvent::Removed(id) => {
-                    self.removed.add(*id);
-                }
+    /// Get the number of edges on the longest path between any two nodes in the tree below
+    /// `root`, including `root` itself.
+    ///
+    /// Returns `0` if `root` has no children.
+    pub fn tree_diameter(&self, root: Entity) -> usize {
+        let (farthest, _) = self.farthest_in_tree(root, root);
+        let (_, distance) = self.farthest_in_tree(root, farthest);
+        distance
+    }
+
+    /// Get how lopsided the subtree below `root` is, as the height of its deepest branch divided
+    /// by the average height of all branches. A branch is a child of `root` with all of its
+    /// recursive children, and its height is the number of edges from `root` to its deepest leaf.
+    ///
+    /// A subtree with branches of equal height has a balance of `1.0`, higher values mean a less
+    /// balanced subtree. Returns `1.0` if `root` has no children.
+    pub fn subtree_balance(&self, root: Entity) -> f32 {
+        let heights = self
+            .children(root)
+            .iter()
+            .map(|child| self.subtree_height(*child) + 1)
+            .collect::<Vec<_>>();
+        match heights.iter().max() {
+            Some(max) => {
+                let average = heights.iter().sum::<usize>() as f32 / heights.len() as f32;
+                *max as f32 / average
             }
+            None => 1.0,
         }
+    }
 
-        // process removed parent components
-        self.scratch_set.clear();
-        for id in (&self.removed).iter() {
-            if let Some(index) = self.entities.get(&id) {
-                self.scratch_set.insert(self.sorted[*index]);
+    /// Get the height of the subtree below `entity`, which is the number of edges from `entity`
+    /// to its deepest recursive child.
+    ///
+    /// Returns `0` for leaves and for entities that are not in the hierarchy. This uses the cached
+    /// depths, so it only walks the subtree once.
+    pub fn subtree_height(&self, entity: Entity) -> usize {
+        // roots do not have a depth, their children are at depth 0
+        let base = self.depth(entity).map(|depth| depth + 1).unwrap_or(0);
+        self.all_children_iter(entity)
+            .filter_map(|child| self.depth(child))
+            .map(|depth| depth + 1 - base)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Get the height of the tallest tree in the hierarchy, see `subtree_height`.
+    ///
+    /// Returns `0` for an empty hierarchy.
+    pub fn max_depth(&self) -> usize {
+        self.iter_sorted()
+            .filter_map(|entity| self.depth(entity))
+            .map(|depth| depth + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Find the node furthest away from `start` within the tree below `root`, walking both up
+    /// and down the hierarchy.
+    fn farthest_in_tree(&self, root: Entity, start: Entity) -> (Entity, usize) {
+        let mut farthest = (start, 0);
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::new();
+        visited.insert(start);
+        queue.push_back((start, 0));
+        while let Some((entity, distance)) = queue.pop_front() {
+            if distance > farthest.1 {
+                farthest = (entity, distance);
             }
-        }
-        // handle parents that have been removed which do not have a Parent themselves
-        for entity in &self.external_parents {
-            if !entities.is_alive(*entity) {
-                self.scratch_set.insert(*entity);
+            let parent = if entity == root {
+                None
+            } else {
+                self.parent(entity)
+            };
+            for next in self.children(entity).iter().cloned().chain(parent) {
+                if visited.insert(next) {
+                    queue.push_back((next, distance + 1));
+                }
             }
         }
+        farthest
+    }
 
-        // do removal
-        if !self.scratch_set.is_empty() {
-            let mut i = 0;
-            let mut min_index = std::usize::MAX;
-            while i < self.sorted.len() {
-                let entity = self.sorted[i];
-                let remove = self.scratch_set.contains(&entity)
-                    || self
-                        .current_parent
-                        .get(&entity)
-                        .map(|parent_entity| self.scratch_set.contains(&parent_entity))
-                        .unwrap_or(false);
-                if remove {
-                    if i < min_index {
-                        min_index = i;
-                    }
-                    self.scratch_set.insert(entity);
-                    self.sorted.remove(i);
-                    if let Some(children) = self
-                        .current_parent
-                        .get(&entity)
-                        .cloned()
-                        .and_then(|parent_entity| self.children.get_mut(&parent_entity))
-                    {
-                        if let Some(pos) = children.iter().position(|e| *e == entity) {
-                            children.swap_remove(pos);
-                        }
-                    }
-                    self.current_parent.remove(&entity);
-                    self.children.remove(&entity);
-                    self.entities.remove(&entity.id());
-                } else {
-                    i += 1;
-                }
-            }
-            for i in min_index..self.sorted.len() {
-                self.entities.insert(self.sorted[i].id(), i);
-            }
-            for entity in &self.scratch_set {
-                self.changed.single_write(HierarchyEvent::Removed(*entity));
-                self.external_parents.remove(entity);
+    /// Get the position of `entity` among all recursive children of `ancestor`, in the order
+    /// given by `all_children_iter`.
+    ///
+    /// Returns `None` if `entity` is not a descendant of `ancestor`.
+    pub fn ordinal_in_subtree(&self, ancestor: Entity, entity: Entity) -> Option<usize> {
+        let mut current = entity;
+        loop {
+            match self.current_parent.get(&current) {
+                Some(parent) if *parent == ancestor => break,
+                Some(parent) => current = *parent,
+                None => return None,
             }
         }
+        self.all_children_iter(ancestor).position(|e| e == entity)
+    }
 
-        // insert new components in hierarchy
-        self.scratch_set.clear();
-        for (entity, _, parent) in (&*entities, &self.inserted, &parents).join() {
-            let parent_entity = parent.parent_entity();
+    /// Get all recursive children of `root`, grouped by their depth below `root`.
+    ///
+    /// The group at index `i` contains the descendants `i + 1` levels below `root`. This does not
+    /// include the root entity you pass in.
+    pub fn subtree_levels(&self, root: Entity) -> Vec<Vec<Entity>> {
+        let mut levels = Vec::new();
+        let mut level = self.children(root).to_vec();
+        while !level.is_empty() {
+            let next = level
+                .iter()
+                .flat_map(|entity| self.children(*entity))
+                .cloned()
+                .collect();
+            levels.push(level);
+            level = next;
+        }
+        levels
+    }
 
-            // if we insert a parent component on an entity that have children, we need to make
-            // sure the parent is inserted before the children in the sorted list
-            let insert_index = self
-                .children
-                .get(&entity)
-                .and_then(|children| {
-                    children
-                        .iter()
-                        .map(|child_entity| self.entities.get(&child_entity.id()).unwrap())
-                        .min()
-                        .cloned()
-                })
-                .unwrap_or_else(|| self.sorted.len());
-            self.entities.insert(entity.id(), insert_index);
-            if insert_index >= self.sorted.len() {
-                self.sorted.push(entity);
-            } else {
-                self.sorted.insert(insert_index, entity);
-                for i in insert_index..self.sorted.len() {
-                    self.entities.insert(self.sorted[i].id(), i);
+    /// Get up to `limit` more recursive children of `root` in breadth-first order.
+    ///
+    /// The `cursor` remembers where the previous call stopped, so repeated calls with the same
+    /// cursor will eventually yield every recursive child exactly once. This does not include the
+    /// root entity you pass in.
+    pub fn descendants_paged(
+        &self,
+        root: Entity,
+        cursor: &mut PageCursor,
+        limit: usize,
+    ) -> Vec<Entity> {
+        if !cursor.started {
+            cursor.started = true;
+            cursor.frontier.extend(self.children(root));
+        }
+        let mut page = Vec::with_capacity(limit.min(cursor.frontier.len()));
+        while page.len() < limit {
+            match cursor.frontier.pop_front() {
+                Some(entity) => {
+                    cursor.frontier.extend(self.children(entity));
+                    page.push(entity);
                 }
+                None => break,
             }
+        }
+        page
+    }
+
+    /// Mark an entity as collapsed, or expanded again.
+    ///
+    /// This does not change the hierarchy, it only affects `visible_descendants`.
+    pub fn set_collapsed(&mut self, entity: Entity, collapsed: bool) {
+        if collapsed {
+            self.collapsed.insert(entity);
+        } else {
+            self.collapsed.remove(&entity);
+        }
+    }
 
+    /// Returns `true` if the entity is marked as collapsed, see `set_collapsed`.
+    pub fn is_collapsed(&self, entity: Entity) -> bool {
+        self.collapsed.contains(&entity)
+    }
+
+    /// Returns an iterator over the recursive children of this entity that are not hidden below a
+    /// collapsed entity, in the same order as `all_children_iter`.
+    ///
+    /// This does not include the parent entity you pass in, and yields nothing if it is collapsed.
+    pub fn visible_descendants<'a>(&'a self, root: Entity) -> impl Iterator<Item = Entity> + 'a {
+        let mut hidden = BitSet::new();
+        self.all_children_iter(root).filter(move |entity| {
+            let parent = self.current_parent[entity];
+            if self.collapsed.contains(&parent) || (parent != root && hidden.contains(parent.id()))
             {
-                let children = self
-                    .children
-                    .entry(parent_entity)
-                    .or_insert_with(Vec::default);
-                children.push(entity);
+                hidden.add(entity.id());
+                false
+            } else {
+                true
             }
+        })
+    }
 
-            self.current_parent.insert(entity, parent_entity);
-            self.scratch_set.insert(entity);
-            if !self.current_parent.contains_key(&parent_entity) {
-                self.external_parents.insert(parent_entity);
-            }
-            self.external_parents.remove(&entity);
+    /// Get the depth of a specific entity, which is its distance from the root of its tree.
+    ///
+    /// The immediate children of a root have a depth of 0. Returns `None` for entities that do not
+    /// have a parent in the hierarchy, including the roots themselves. The depths are cached
+    /// during `maintain`, so this is a constant time lookup.
+    pub fn depth(&self, entity: Entity) -> Option<usize> {
+        self.depths.get(&entity).cloned()
+    }
+
+    /// Get the depth `entity` would have if it was moved below `new_parent`, without moving it.
+    ///
+    /// Returns `None` if the move would create a cycle, because `new_parent` is `entity` itself or
+    /// one of its recursive children.
+    pub fn depth_after_reparent(&self, entity: Entity, new_parent: Entity) -> Option<usize> {
+        if new_parent == entity || self.is_ancestor(entity, new_parent) {
+            return None;
         }
+        Some(self.depth(new_parent).map(|depth| depth + 1).unwrap_or(0))
+    }
 
-        for (entity, _, parent) in (&*entities, &self.modified.clone(), &parents).join() {
-            let parent_entity = parent.parent_entity();
-            // if theres an old parent
-            if let Some(old_parent) = self.current_parent.get(&entity).cloned() {
-                // if the parent entity was not changed, ignore event
-                if old_parent == parent_entity {
-                    continue;
-                }
-                // remove entity from old parents children
-                if let Some(children) = self.children.get_mut(&old_parent) {
-                    if let Some(pos) = children.iter().position(|e| *e == entity) {
-                        children.remove(pos);
-                    }
+    /// Get all entities with exactly the given depth in sorted order, see `depth`.
+    ///
+    /// A depth of 0 gives the immediate children of all roots.
+    pub fn entities_at_depth(&self, depth: usize) -> Vec<Entity> {
+        self.in_depth_range(depth, depth)
+    }
+
+    /// Get all entities with a depth of at least `min` and at most `max` in sorted order, see
+    /// `depth`.
+    pub fn in_depth_range(&self, min: usize, max: usize) -> Vec<Entity> {
+        self.iter_sorted()
+            .filter(|entity| {
+                self.depth(*entity)
+                    .map(|depth| min <= depth && depth <= max)
+                    .unwrap_or(false)
+            })
+            .collect()
+    }
+
+    /// Get the entities whose depth was changed by the last `maintain`, while their parent was
+    /// not, in sorted order. This happens when one of their ancestors is reparented.
+    ///
+    /// Structural changes made directly on the hierarchy, like `remove_subtree_to_depth`, will
+    /// also update this list.
+    pub fn depth_changed_entities(&self) -> &[Entity] {
+        self.depth_changed.as_slice()
+    }
+
+    /// Get the number of recursive children of this entity, see `all_children`.
+    ///
+    /// The counts are kept up to date incrementally during `maintain`, so this is a constant time
+    /// lookup.
+    pub fn descendant_count(&self, entity: Entity) -> usize {
+        self.descendant_counts.get(&entity).cloned().unwrap_or(0)
+    }
+
+    /// Get the immediate children of this entity, each with its `descendant_count`.
+    pub fn children_with_counts(&self, entity: Entity) -> Vec<(Entity, usize)> {
+        self.children(entity)
+            .iter()
+            .map(|child| (*child, self.descendant_count(*child)))
+            .collect()
+    }
+
+    /// Get the entities whose parent is not in `partition`, in sorted order.
+    ///
+    /// Whether the entities themselves are in `partition` does not matter.
+    pub fn cross_boundary_children(&self, partition: &BitSet) -> Vec<Entity> {
+        self.iter_sorted()
+            .filter(|entity| !partition.contains(self.current_parent[entity].id()))
+            .collect()
+    }
+
+    /// Get the entities that have a parent in the hierarchy, but no longer have a component in
+    /// `parents`, in sorted order.
+    ///
+    /// This is expected between removing components and the next `maintain`, anything else means
+    /// the hierarchy is out of sync with the component storage.
+    pub fn entities_missing_component<'a>(&self, parents: &ReadStorage<'a, P>) -> Vec<Entity>
+    where
+        P: Component,
+    {
+        self.iter_sorted()
+            .filter(|entity| parents.get(*entity).is_none())
+            .collect()
+    }
+
+    /// Find every child that appears more than once in the children of its parent, as
+    /// `(parent, duplicated_child)` pairs ordered by parent.
+    ///
+    /// This should always be empty; anything else means the hierarchy has been corrupted.
+    pub fn find_duplicate_children(&self) -> Vec<(Entity, Entity)> {
+        let mut duplicates = Vec::new();
+        for (parent, children) in &self.children {
+            let mut seen = HashSet::new();
+            let mut reported = HashSet::new();
+            for child in children {
+                if !seen.insert(*child) && reported.insert(*child) {
+                    duplicates.push((*parent, *child));
                 }
             }
+        }
+        duplicates.sort();
+        duplicates
+    }
 
-            // insert in new parents children
-            self.children
-                .entry(parent_entity)
-                .or_insert_with(Vec::default)
-                .push(entity);
-
-            // move entity in sorted if needed
-            let entity_index = self.entities.get(&entity.id()).cloned().unwrap();
-            if let Some(parent_index) = self.entities.get(&parent_entity.id()).cloned() {
-                let mut offset = 0;
-                let mut process_index = parent_index;
-                while process_index > entity_index {
-                    let move_entity = self.sorted.remove(process_index);
-                    self.sorted.insert(entity_index, move_entity);
-                    offset += 1;
-                    process_index = self
-                        .current_parent
-                        .get(&move_entity)
-                        .and_then(|p_entity| self.entities.get(&p_entity.id()))
-                        .map(|p_index| p_index + offset)
-                        .unwrap_or(0);
-                }
+    /// Get the entities that were already in the hierarchy before the last `maintain`, but whose
+    /// index in `all` was changed by it, in sorted order.
+    ///
+    /// Structural changes made directly on the hierarchy, like `append_leaf`, are not included.
+    pub fn moved_in_sorted(&self) -> &[Entity] {
+        self.moved.as_slice()
+    }
 
-                // fix indexes
-                if parent_index > entity_index {
-                    for i in entity_index..parent_index {
-                        self.entities.insert(self.sorted[i].id(), i);
-                    }
-                }
+    /// Get the number of immediate children of this entity.
+    pub fn child_count(&self, entity: Entity) -> usize {
+        self.children(entity).len()
+    }
+
+    /// Returns `true` if this entity has no children.
+    ///
+    /// This is also `true` for entities that are not part of the hierarchy at all.
+    pub fn is_leaf(&self, entity: Entity) -> bool {
+        self.children(entity).is_empty()
+    }
+
+    /// Returns `true` if this entity has children, but no parent itself, see `roots`.
+    pub fn is_root(&self, entity: Entity) -> bool {
+        !self.current_parent.contains_key(&entity) && !self.is_leaf(entity)
+    }
+
+    /// Returns `true` if this entity has a parent, and is the only child of that parent.
+    pub fn is_only_child(&self, entity: Entity) -> bool {
+        self.parent(entity)
+            .map(|parent| self.children(parent).len() == 1)
+            .unwrap_or(false)
+    }
+
+    /// Get the chain from this entity up to the root of its tree, as
+    /// `[entity, parent, grandparent, ..., root]`.
+    ///
+    /// Unlike `ancestors`, this includes both the entity itself and the root. An entity without a
+    /// parent gives a single element vec.
+    pub fn path_to_root(&self, entity: Entity) -> Vec<Entity> {
+        // a valid chain can not be longer than the number of entities in the hierarchy
+        let max_len = self.sorted.len() + self.external_parents.len();
+        let mut path = vec![entity];
+        let mut current = entity;
+        while let Some(parent) = self.current_parent.get(&current) {
+            if path.len() > max_len {
+                break;
+            }
+            path.push(*parent);
+            current = *parent;
+        }
+        path
+    }
+
+    /// Returns an iterator over the ancestors of this entity, starting with its parent and
+    /// walking up the hierarchy.
+    ///
+    /// The root of the tree, which has no parent itself, is not included, see `ancestor_set` for
+    /// that.
+    pub fn ancestors(&self, entity: Entity) -> AncestorsIterator<'_, P> {
+        AncestorsIterator {
+            hierarchy: self,
+            current: entity,
+        }
+    }
+
+    /// Take the summary of the changes made by the last `maintain`, see `FrameDelta`.
+    ///
+    /// Calling this again before the next `maintain` returns an empty summary. Structural changes
+    /// made directly on the hierarchy, like `append_leaf`, are not included.
+    pub fn take_frame_delta(&mut self) -> FrameDelta {
+        std::mem::take(&mut self.frame_delta)
+    }
+
+    /// Get the parent of a specific entity
+    pub fn parent(&self, entity: Entity) -> Option<Entity> {
+        self.current_parent.get(&entity).cloned()
+    }
+
+    /// Get the top layer of every tree: the immediate children of all roots, in sorted order.
+    pub fn first_layer(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.iter_sorted().filter(move |entity| {
+            self.current_parent
+                .get(entity)
+                .map(|parent| !self.current_parent.contains_key(parent))
+                .unwrap_or(false)
+        })
+    }
+
+    /// Call `f` once for every tree in the hierarchy, with the root of the tree and an iterator
+    /// over all nodes of that tree, starting with the root itself.
+    ///
+    /// Trees are visited in the order their first child appears in `all`, and the nodes of each
+    /// tree are given in the same order as `all_children_iter`.
+    pub fn for_each_tree<F>(&self, mut f: F)
+    where
+        F: FnMut(Entity, &mut dyn Iterator<Item = Entity>),
+    {
+        for root in self.roots() {
+            f(
+                root,
+                &mut Some(root).into_iter().chain(self.all_children_iter(root)),
+            );
+        }
+    }
+
+    /// Returns `true` if nothing in the subtree of `root`, including `root` itself, was inserted,
+    /// modified or removed during the last `maintain`.
+    pub fn subtree_clean(&self, root: Entity) -> bool {
+        !self.dirty.contains(root.id())
+            && self
+                .all_children_iter(root)
+                .all(|entity| !self.dirty.contains(entity.id()))
+    }
+
+    /// Returns `true` if `ancestor` is a parent, grandparent and so on of `descendant`.
+    ///
+    /// An entity is not its own ancestor.
+    pub fn is_ancestor(&self, ancestor: Entity, descendant: Entity) -> bool {
+        let mut current = descendant;
+        // a corrupted hierarchy could contain a cycle, which must not hang the walk
+        for _ in 0..=self.sorted.len() {
+            match self.current_parent.get(&current) {
+                Some(parent) if *parent == ancestor => return true,
+                Some(parent) => current = *parent,
+                None => return false,
             }
+        }
+        false
+    }
 
-            self.current_parent.insert(entity, parent_entity);
-            self.scratch_set.insert(entity);
+    /// Returns `true` if `descendant` is a child, grandchild and so on of `ancestor`, see
+    /// `is_ancestor`.
+    pub fn is_descendant(&self, descendant: Entity, ancestor: Entity) -> bool {
+        self.is_ancestor(ancestor, descendant)
+    }
 
-            if !self.current_parent.contains_key(&parent_entity) {
-                self.external_parents.insert(parent_entity);
+    /// Walk up the hierarchy from `entity` and get the nearest ancestor for which `pred` returns
+    /// `true`.
+    ///
+    /// The entity itself is not tested. Returns `None` if no ancestor up to and including the root
+    /// matches.
+    pub fn find_ancestor<F>(&self, entity: Entity, mut pred: F) -> Option<Entity>
+    where
+        F: FnMut(Entity) -> bool,
+    {
+        let mut current = entity;
+        while let Some(parent) = self.current_parent.get(&current) {
+            if pred(*parent) {
+                return Some(*parent);
             }
+            current = *parent;
         }
+        None
+    }
 
-        if !self.scratch_set.is_empty() {
-            for i in 0..self.sorted.len() {
-                let entity = self.sorted[i];
-                let notify = self.scratch_set.contains(&entity)
-                    || self
-                        .current_parent
-                        .get(&entity)
-                        .map(|parent_entity| self.scratch_set.contains(&parent_entity))
-                        .unwrap_or(false);
-                if notify {
-                    self.scratch_set.insert(entity);
-                    self.changed.single_write(HierarchyEvent::Modified(entity));
+    /// Group the given entities by the root of the tree they belong to.
+    ///
+    /// A root is grouped under itself. Entities that are not part of any tree, meaning they have
+    /// neither a parent nor children, are skipped. Within each group the entities keep the order
+    /// they were given in.
+    pub fn group_by_root<I>(&self, entities: I) -> HashMap<Entity, Vec<Entity>>
+    where
+        I: IntoIterator<Item = Entity>,
+    {
+        let mut roots = HashMap::new();
+        let mut groups = HashMap::<Entity, Vec<Entity>>::new();
+        let mut path = Vec::new();
+        for entity in entities {
+            if !self.current_parent.contains_key(&entity) && self.children(entity).is_empty() {
+                continue;
+            }
+            let mut current = entity;
+            let root = loop {
+                if let Some(root) = roots.get(&current) {
+                    break *root;
                 }
+                match self.current_parent.get(&current) {
+                    Some(parent) => {
+                        path.push(current);
+                        current = *parent;
+                    }
+                    None => break current,
+                }
+            };
+            for visited in path.drain(..) {
+                roots.insert(visited, root);
             }
+            groups.entry(root).or_default().push(entity);
         }
+        groups
+    }
 
-        self.scratch_set.clear();
-        for entity in &self.external_parents {
-            if !self.children.contains_key(entity) {
-                self.scratch_set.insert(*entity);
+    /// Get the deepest entity that is either an ancestor of, or one of, every given entity.
+    ///
+    /// Returns `None` if no entities are given, or if they are not all in the same tree.
+    pub fn common_ancestor_of<I>(&self, entities: I) -> Option<Entity>
+    where
+        I: IntoIterator<Item = Entity>,
+    {
+        let mut entities = entities.into_iter();
+        let first = entities.next()?;
+        entities.try_fold(first, |ancestor, entity| {
+            self.common_ancestor(ancestor, entity)
+        })
+    }
+
+    /// Get the deepest entity that is either an ancestor of, or one of, both `a` and `b`.
+    ///
+    /// If one of the entities is an ancestor of the other, that entity is returned. Returns
+    /// `None` if `a` and `b` are not in the same tree.
+    pub fn common_ancestor(&self, a: Entity, b: Entity) -> Option<Entity> {
+        let mut chain = HashSet::new();
+        chain.insert(a);
+        let mut current = a;
+        while let Some(parent) = self.current_parent.get(&current) {
+            chain.insert(*parent);
+            current = *parent;
+        }
+        let mut current = b;
+        loop {
+            if chain.contains(&current) {
+                return Some(current);
             }
+            current = *self.current_parent.get(&current)?;
         }
-        for entity in &self.scratch_set {
-            self.external_parents.remove(entity);
+    }
+
+    /// Get the sum of `weight(parent, child)` over every edge on the path between `a` and `b`,
+    /// which goes through their common ancestor, see `common_ancestor`.
+    ///
+    /// Returns `None` if `a` and `b` are not in the same tree.
+    pub fn weighted_distance<F>(&self, a: Entity, b: Entity, weight: F) -> Option<f32>
+    where
+        F: Fn(Entity, Entity) -> f32,
+    {
+        let ancestor = self.common_ancestor(a, b)?;
+        let mut distance = 0.0;
+        for start in &[a, b] {
+            let mut current = *start;
+            while current != ancestor {
+                let parent = self.current_parent[&current];
+                distance += weight(parent, current);
+                current = parent;
+            }
         }
+        Some(distance)
     }
-}
 
-pub struct SubHierarchyIterator<'a, P>
-where
-    P: 'a,
-{
-    current_index: usize,
-    end_index: usize,
-    hierarchy: &'a Hierarchy<P>,
-    entities: BitSet,
-}
+    /// Get all entities that have a parent, but are not parents themselves, in sorted order.
+    ///
+    /// Note: This does not include root entities without any children.
+    pub fn parented_leaves(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.iter_sorted().filter(move |entity| {
+            self.children
+                .get(entity)
+                .map(|children| children.is_empty())
+                .unwrap_or(true)
+        })
+    }
 
-impl<'a, P> SubHierarchyIterator<'a, P>
-where
-    P: 'a,
-{
-    fn new(hierarchy: &'a Hierarchy<P>, root: Entity) -> Self {
-        let max = hierarchy.sorted.len();
-        let root_index = hierarchy
-            .children
-            .get(&root)
-            .map(|children| {
-                children
+    /// Get the index of this entity in the sorted order, see `all`.
+    ///
+    /// Parents always have a lower index than their children, so this can be used to sort other
+    /// data in hierarchy order. The index is only valid until the next `maintain`, which can move
+    /// entities around in the sorted order.
+    pub fn sort_index(&self, entity: Entity) -> Option<usize> {
+        self.index_of(entity)
+    }
+
+    /// Get the index of this entity in a pre-order walk of the whole forest, see `next_in_order`.
+    ///
+    /// Unlike `sort_index`, this counts the roots as well, and every subtree takes up a
+    /// contiguous range of indices. The index is not cached: this walks up to the root, and skips
+    /// the subtrees of all earlier siblings and earlier trees along the way, which takes time
+    /// linear in the size of the hierarchy. To number every entity, step through the forest with
+    /// `next_in_order` instead.
+    pub fn preorder_index(&self, entity: Entity) -> Option<usize> {
+        let before = |siblings: &[Entity], current: Entity| {
+            let position = siblings.iter().position(|e| *e == current)?;
+            Some(
+                siblings[..position]
                     .iter()
-                    .map(|c| hierarchy.entities.get(&c.id()).cloned().unwrap_or(max))
-                    .min()
-                    .unwrap_or(max)
-            })
-            .unwrap_or(max);
-        let mut iter = SubHierarchyIterator {
-            hierarchy,
-            current_index: root_index,
-            end_index: 0,
-            entities: BitSet::new(),
+                    .map(|sibling| self.descendant_count(*sibling) + 1)
+                    .sum::<usize>(),
+            )
         };
-        iter.process_entity(root);
-        if root_index != max {
-            iter.process_entity(hierarchy.sorted[root_index]);
+        let mut index = 0;
+        let mut current = entity;
+        while let Some(parent) = self.parent(current) {
+            index += 1 + before(self.children(parent), current)?;
+            current = parent;
         }
-        iter
+        Some(index + before(&self.roots(), current)?)
     }
 
-    fn process_entity(&mut self, child: Entity) {
-        if let Some(children) = self.hierarchy.children.get(&child) {
-            for child in children {
-                self.entities.add(child.id());
-                if let Some(index) = self.hierarchy.entities.get(&child.id()) {
-                    if *index > self.end_index {
-                        self.end_index = *index;
-                    }
-                }
-            }
-        }
+    /// Get the entity at the given index in the sorted order, see `all`. This is the inverse of
+    /// `sort_index`.
+    pub fn entity_at_rank(&self, rank: usize) -> Option<Entity> {
+        self.sorted
+            .get(rank)
+            .cloned()
+            .filter(|entity| !self.tombstones.contains(entity))
     }
-}
 
-impl<'a, P> Iterator for SubHierarchyIterator<'a, P>
-where
-    P: 'a,
-{
-    type Item = Entity;
+    /// Get all entities that contain parents in the reverse of the sorted order, where children
+    /// are guaranteed to be before their parents. Deleting entities in this order never leaves a
+    /// child without its parent.
+    ///
+    /// Note: Like `all`, this does not include entities that are only parents.
+    pub fn destruction_order(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.sorted
+            .iter()
+            .rev()
+            .cloned()
+            .filter(move |entity| !self.tombstones.contains(entity))
+    }
 
-    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-        if self.current_index >= self.hierarchy.sorted.len() || self.current_index > self.end_index
-        {
-            None
-        } else {
-            let entity = self.hierarchy.sorted[self.current_index];
-            let mut found_next = false;
-            while !found_next {
-                self.current_index += 1;
-                if self.current_index > self.end_index
-                    || self.current_index >= self.hierarchy.sorted.len()
-                {
-                    found_next = true;
-                } else if self
-                    .entities
-                    .contains(self.hierarchy.sorted[self.current_index].id())
-                {
-                    found_next = true;
-                    let current_index = self.current_index; // compiler fails to realise a usize is Copy, so we break it out
-                    self.process_entity(self.hierarchy.sorted[current_index]);
-                }
-            }
-            Some(entity)
+    /// Get the entity right before this entity in the sorted order, see `all`.
+    pub fn predecessor(&self, entity: Entity) -> Option<Entity> {
+        self.index_of(entity).and_then(|index| {
+            self.sorted[..index]
+                .iter()
+                .rev()
+                .find(|e| !self.tombstones.contains(e))
+                .cloned()
+        })
+    }
+
+    /// Get the entity right after this entity in the sorted order, see `all`.
+    pub fn successor(&self, entity: Entity) -> Option<Entity> {
+        self.index_of(entity).and_then(|index| {
+            self.sorted[index + 1..]
+                .iter()
+                .find(|e| !self.tombstones.contains(e))
+                .cloned()
+        })
+    }
+
+    fn index_of(&self, entity: Entity) -> Option<usize> {
+        self.entities
+            .get(&entity.id())
+            .cloned()
+            .filter(|index| self.sorted[*index] == entity)
+    }
+
+    /// Iterate the sorted list, skipping entities that were lazily removed.
+    fn iter_sorted(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.sorted
+            .iter()
+            .cloned()
+            .filter(move |entity| !self.tombstones.contains(entity))
+    }
+
+    /// Record a reference from one entity to another, e.g. an attachment point in another tree.
+    ///
+    /// Note: References are **not** part of the parent-child tree. They do not affect the sorted
+    /// order, and removals from the hierarchy do not cascade through them.
+    pub fn add_reference(&mut self, from: Entity, to: Entity) {
+        self.references.entry(from).or_default().push(to);
+    }
+
+    /// Get the entities referenced by a specific entity, see `add_reference`.
+    pub fn references(&self, entity: Entity) -> &[Entity] {
+        self.references
+            .get(&entity)
+            .map(|vec| vec.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Compute a fingerprint of the parent-child links in the hierarchy.
+    ///
+    /// Two hierarchies with the same links produce the same hash, regardless of the sorted order
+    /// or the order the links were added in.
+    pub fn structural_hash(&self) -> u64 {
+        self.current_parent
+            .iter()
+            .fold(0u64, |hash, (child, parent)| {
+                hash.wrapping_add(link_hash(*child, *parent))
+            })
+    }
+
+    /// Compute a fingerprint of the subtree of `root`, from `root` itself and the parent-child
+    /// links below it.
+    ///
+    /// Like `structural_hash`, this does not depend on the sorted order, so the same subtree
+    /// produces the same checksum in different worlds, as long as the entities match.
+    pub fn subtree_checksum(&self, root: Entity) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        root.hash(&mut hasher);
+        self.all_children_iter(root)
+            .fold(hasher.finish(), |hash, child| {
+                hash.wrapping_add(link_hash(child, self.current_parent[&child]))
+            })
+    }
+
+    /// Take a snapshot of the parent-child links in the hierarchy, including the order of
+    /// children.
+    ///
+    /// The snapshot can be compared against later with `changed_since`, or used to rebuild the
+    /// hierarchy with `from_snapshot`.
+    pub fn snapshot(&self) -> HierarchySnapshot {
+        let mut children = self
+            .children
+            .iter()
+            .map(|(parent, children)| {
+                let children = children.iter().map(|child| (*child).into()).collect();
+                ((*parent).into(), children)
+            })
+            .collect::<Vec<_>>();
+        children.sort();
+        HierarchySnapshot {
+            parents: self.snapshot_parents(),
+            children,
         }
     }
-}
 
-/// Bound for the parent component of your crate. Your `Parent` component usually just contains the
-/// `Entity` that's the parent you're linking to.
-///
-/// Note that the component should indicate that the `Entity` its added *has* a parent (the entity
-/// stored in your component).
-pub trait Parent {
-    /// Retrieves the parent `Entity`.
-    fn parent_entity(&self) -> Entity;
-}
+    /// Check if any parent-child link was added, removed or changed since `snapshot` was taken.
+    ///
+    /// Changes to the order of children are not considered.
+    pub fn changed_since(&self, snapshot: &HierarchySnapshot) -> bool {
+        self.snapshot_parents() != snapshot.parents
+    }
 
-/// Utility struct for the data needed by the `Hierarchy` maintain.
-#[derive(SystemData)]
-pub struct ParentData<'a, P>
-where
-    P: Component + Parent,
-    P::Storage: Tracked,
-{
-    entities: Entities<'a>,
-    parents: ReadStorage<'a, P>,
-}
+    /// Get the children of every parent in the hierarchy as an adjacency list, from the index of
+    /// each parent to the indices of its children, in the order of `children`.
+    ///
+    /// Entities are only identified by their index, the generation is dropped. Parents that have
+    /// no children left are not included.
+    pub fn to_adjacency(&self) -> HashMap<Index, Vec<Index>> {
+        self.children
+            .iter()
+            .filter(|(_, children)| !children.is_empty())
+            .map(|(parent, children)| {
+                (
+                    parent.id(),
+                    children.iter().map(|child| child.id()).collect(),
+                )
+            })
+            .collect()
+    }
+
+    /// Render the hierarchy as a Graphviz digraph, for debugging.
+    ///
+    /// There is one node for every entity in the hierarchy, including the roots, labeled with
+    /// the entity id, and one edge from each parent to each of its children.
+    pub fn to_dot(&self) -> String {
+        self.to_dot_with(|entity| entity.id().to_string())
+    }
+
+    /// Like `to_dot`, but with node labels given by `label`.
+    pub fn to_dot_with<F>(&self, label: F) -> String
+    where
+        F: Fn(Entity) -> String,
+    {
+        let mut dot = String::from("digraph hierarchy {\n");
+        for entity in self.roots().into_iter().chain(self.iter_sorted()) {
+            dot.push_str(&format!(
+                "    {} [label=\"{}\"];\n",
+                entity.id(),
+                label(entity).replace('\\', "\\\\").replace('"', "\\\"")
+            ));
+        }
+        for entity in self.iter_sorted() {
+            dot.push_str(&format!(
+                "    {} -> {};\n",
+                self.current_parent[&entity].id(),
+                entity.id()
+            ));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn snapshot_parents(&self) -> Vec<(SnapshotEntity, SnapshotEntity)> {
+        let mut parents = self
+            .current_parent
+            .iter()
+            .map(|(child, parent)| ((*child).into(), (*parent).into()))
+            .collect::<Vec<_>>();
+        parents.sort();
+        parents
+    }
+
+    /// Get the parent of a specific entity, together with its siblings.
+    ///
+    /// The siblings are in the same order as `children` of the parent, and do not include the
+    /// entity itself. Returns `None` if the entity has no parent.
+    pub fn parent_and_siblings(&self, entity: Entity) -> Option<(Entity, Vec<Entity>)> {
+        self.parent(entity).map(|parent| {
+            let siblings = self
+                .children(parent)
+                .iter()
+                .cloned()
+                .filter(|e| *e != entity)
+                .collect();
+            (parent, siblings)
+        })
+    }
+
+    /// Get the other children of this entity's parent, in the same order as `children` of the
+    /// parent.
+    ///
+    /// Returns an empty `Vec` if the entity has no parent.
+    pub fn siblings(&self, entity: Entity) -> Vec<Entity> {
+        self.parent_and_siblings(entity)
+            .map(|(_, siblings)| siblings)
+            .unwrap_or_default()
+    }
+
+    /// Get the first sibling after this entity, in the order of `children` of its parent, for
+    /// which `pred` returns `true`.
+    pub fn next_sibling_where<F>(&self, entity: Entity, mut pred: F) -> Option<Entity>
+    where
+        F: FnMut(Entity) -> bool,
+    {
+        let siblings = self.children(self.parent(entity)?);
+        let position = siblings.iter().position(|e| *e == entity)?;
+        siblings[position + 1..]
+            .iter()
+            .cloned()
+            .find(|sibling| pred(*sibling))
+    }
+
+    /// Get the first sibling before this entity, walking backwards in the order of `children` of
+    /// its parent, for which `pred` returns `true`.
+    pub fn prev_sibling_where<F>(&self, entity: Entity, mut pred: F) -> Option<Entity>
+    where
+        F: FnMut(Entity) -> bool,
+    {
+        let siblings = self.children(self.parent(entity)?);
+        let position = siblings.iter().position(|e| *e == entity)?;
+        siblings[..position]
+            .iter()
+            .rev()
+            .cloned()
+            .find(|sibling| pred(*sibling))
+    }
+
+    /// Get the entity after this one in a pre-order walk of the whole forest, following the order
+    /// of `children` and `roots`.
+    ///
+    /// This is the first child of the entity, or else the next sibling of the entity or of its
+    /// nearest ancestor that has one. After the last entity of a tree comes the root of the next
+    /// tree.
+    pub fn next_in_order(&self, entity: Entity) -> Option<Entity> {
+        if let Some(child) = self.children(entity).first() {
+            return Some(*child);
+        }
+        let mut current = entity;
+        while let Some(parent) = self.parent(current) {
+            let siblings = self.children(parent);
+            let position = siblings.iter().position(|e| *e == current)?;
+            if let Some(next) = siblings.get(position + 1) {
+                return Some(*next);
+            }
+            current = parent;
+        }
+        let roots = self.roots();
+        let position = roots.iter().position(|e| *e == current)?;
+        roots.get(position + 1).cloned()
+    }
+
+    /// Get the entity before this one in a pre-order walk of the whole forest, see
+    /// `next_in_order`.
+    pub fn prev_in_order(&self, entity: Entity) -> Option<Entity> {
+        let previous = match self.parent(entity) {
+            Some(parent) => {
+                let siblings = self.children(parent);
+                let position = siblings.iter().position(|e| *e == entity)?;
+                if position == 0 {
+                    return Some(parent);
+                }
+                siblings[position - 1]
+            }
+            None => {
+                let roots = self.roots();
+                let position = roots.iter().position(|e| *e == entity)?;
+                roots[position.checked_sub(1)?]
+            }
+        };
+        // the last entity in the subtree of the previous sibling
+        let mut last = previous;
+        while let Some(child) = self.children(last).last() {
+            last = *child;
+        }
+        Some(last)
+    }
+
+    /// Get the zero-based position of this entity among the children of its parent, when they
+    /// are sorted by `key`. Children with equal keys keep the order of `children` of the parent.
+    ///
+    /// Returns `None` if the entity has no parent.
+    pub fn sibling_rank_by<K, F>(&self, entity: Entity, key: F) -> Option<usize>
+    where
+        K: Ord,
+        F: Fn(Entity) -> K,
+    {
+        let mut siblings = self.children(self.parent(entity)?).to_vec();
+        siblings.sort_by_cached_key(|sibling| key(*sibling));
+        siblings.iter().position(|e| *e == entity)
+    }
+
+    /// Get the parent, siblings and direct children of this entity in one go.
+    pub fn neighborhood(&self, entity: Entity) -> Neighborhood {
+        let (parent, siblings) = match self.parent_and_siblings(entity) {
+            Some((parent, siblings)) => (Some(parent), siblings),
+            None => (None, Vec::new()),
+        };
+        Neighborhood {
+            parent,
+            siblings,
+            children: self.children(entity).to_vec(),
+        }
+    }
+
+    /// Get a token for tracking the modification events from the hierarchy
+    ///
+    /// Note: Events are only sent while at least one token is alive, a new token will only
+    /// receive the events sent after its creation.
+    pub fn track(&mut self) -> ReaderId<HierarchyEvent> {
+        self.changed.register_reader()
+    }
+
+    /// Get a reader for the modification events that affect the subtree of `root`, including
+    /// `root` itself, see `SubtreeReader`.
+    pub fn subtree_reader(&mut self, root: Entity) -> SubtreeReader {
+        SubtreeReader {
+            root,
+            members: self.subtree_set(root),
+            reader_id: self.track(),
+        }
+    }
+
+    /// Get the `EventChannel` for the modification events for reading
+    pub fn changed(&self) -> &EventChannel<HierarchyEvent> {
+        &self.changed
+    }
+
+    /// Get the number of times `maintain` has been run on this hierarchy.
+    pub fn maintain_count(&self) -> u64 {
+        self.tick
+    }
+
+    /// Set how many of the most recent modification events are kept in the change history, see
+    /// `change_history`. A capacity of `0`, the default, disables the history.
+    ///
+    /// Shrinking the capacity drops the oldest recorded events.
+    pub fn set_history_capacity(&mut self, capacity: usize) {
+        self.history_capacity = capacity;
+        while self.history.len() > capacity {
+            self.history.pop_front();
+        }
+    }
+
+    /// Get the recorded modification events from oldest to newest, each stamped with the
+    /// `maintain_count` at the time it was sent.
+    ///
+    /// Events sent during a `maintain` carry the count including that `maintain`. Unlike the
+    /// `EventChannel`, the history is recorded even if nothing is tracking the hierarchy.
+    pub fn change_history(&self) -> impl Iterator<Item = (u64, HierarchyEvent)> + '_ {
+        self.history.iter().cloned()
+    }
+
+    /// Set where `maintain` places reparented entities among the children of their new parent.
+    ///
+    /// Defaults to `ReparentPlacement::End`.
+    pub fn set_reparent_placement(&mut self, placement: ReparentPlacement) {
+        self.reparent_placement = placement;
+    }
+
+    /// Get where `maintain` places reparented entities among the children of their new parent.
+    pub fn reparent_placement(&self) -> ReparentPlacement {
+        self.reparent_placement
+    }
+
+    /// Set a handler that is called by `maintain` with `(child, attempted_parent)` whenever a
+    /// parent link is ignored because it would create a cycle.
+    ///
+    /// When a link is ignored, the child keeps its previous parent, or is not added to the
+    /// cycles.
+    ///
+    /// Ignored links are retried on every `maintain` for as long as the `Parent` component of the
+    /// child is unchanged, so the child is linked once the cycle is broken, for example by moving
+    /// one of its ancestors. The handler is only called the first time a link is ignored.
+    pub fn set_cycle_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(Entity, Entity) + Send + Sync + 'static,
+    {
+        self.cycle_handler = Some(Box::new(handler));
+    }
+
+    /// Enable or disable sorting the children of `parent` during `maintain`, using the comparator
+    /// given to `set_child_comparator`.
+    ///
+    /// Children of parents without auto sorting keep the order they were added in.
+    pub fn set_auto_sort(&mut self, parent: Entity, enabled: bool) {
+        if enabled {
+            self.auto_sorted.insert(parent);
+        } else {
+            self.auto_sorted.remove(&parent);
+        }
+    }
+
+    /// Set the comparator used to sort the children of parents with auto sorting enabled, see
+    /// `set_auto_sort`. The sort is stable.
+    pub fn set_child_comparator<F>(&mut self, comparator: F)
+    where
+        F: Fn(&Entity, &Entity) -> Ordering + Send + Sync + 'static,
+    {
+        self.child_comparator = Some(Box::new(comparator));
+    }
+
+    /// Assert that the parent of every entity in the hierarchy matches `expected`.
+    ///
+    /// Each entry in `expected` is an entity and the parent it should have, or `None` if it should
+    /// not have a parent in the hierarchy. Entities that have a parent in the hierarchy but are
+    /// missing from `expected` are also reported.
+    ///
+    /// Panics with a description of every mismatch.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn assert_structure(&self, expected: &[(Entity, Option<Entity>)]) {
+        let mut errors = Vec::new();
+        for (entity, parent) in expected {
+            let actual = self.parent(*entity);
+            if actual != *parent {
+                errors.push(format!(
+                    "{:?}: expected parent {:?}, found {:?}",
+                    entity, parent, actual
+                ));
+            }
+        }
+        for entity in self.iter_sorted() {
+            if !expected.iter().any(|(e, _)| *e == entity) {
+                errors.push(format!(
+                    "{:?}: has parent {:?}, but is missing from the expected structure",
+                    entity,
+                    self.parent(entity)
+                ));
+            }
+        }
+        if !errors.is_empty() {
+            panic!("hierarchy structure mismatch:\n{}", errors.join("\n"));
+        }
+    }
+
+    /// Enable or disable coalescing of `Modified` events.
+    ///
+    /// With coalescing enabled, each `maintain` sends at most one `Modified` event per entity,
+    /// however many times the entity was changed during it. Events sent outside of `maintain` are
+    /// not coalesced, use `begin_batch` to group those.
+    pub fn set_coalesce_events(&mut self, coalesce: bool) {
+        self.coalesce_events = coalesce;
+    }
+
+    /// Enable or disable lazy removal.
+    ///
+    /// With lazy removal enabled, entities removed by `maintain` are only marked as removed, and
+    /// the hierarchy is compacted in a single pass before `maintain` inserts or reparents entities,
+    /// or returns. This makes frames that remove many entities cheaper, since the remaining
+    /// entities are moved and reindexed once, instead of once for every removed entity.
+    ///
+    /// Removed entities never remain after `maintain`, so all queries, including `all`, only see
+    /// the entities in the hierarchy. Disabling lazy removal compacts the hierarchy.
+    pub fn set_lazy_removal(&mut self, lazy: bool) {
+        self.lazy_removal = lazy;
+        if !lazy {
+            self.compact();
+        }
+    }
+
+    /// Remove all lazily removed entities from the sorted list, see `set_lazy_removal`.
+    ///
+    /// `maintain` already does this before it returns.
+    pub fn compact(&mut self) {
+        if self.tombstones.is_empty() {
+            return;
+        }
+        let tombstones = &self.tombstones;
+        self.sorted.retain(|entity| !tombstones.contains(entity));
+        self.tombstones.clear();
+        for (i, entity) in self.sorted.iter().enumerate() {
+            self.entities.insert(entity.id(), i);
+        }
+    }
+
+    /// Remove all entities from the hierarchy, bypassing the component event reader.
+    ///
+    /// Will send a `Removed` event for every entity in the hierarchy, in sorted order. Settings and
+    /// references are kept, while the results of the last `maintain`, open batches and rejected
+    /// links are reset. The allocated memory is kept for reuse, see `shrink_to_fit`.
+    ///
+    /// Note: The caller must also remove the `Parent` components, or later calls to `maintain`
+    /// will not be consistent with the hierarchy.
+    pub fn clear(&mut self) {
+        self.compact();
+        for i in 0..self.sorted.len() {
+            let entity = self.sorted[i];
+            self.emit(HierarchyEvent::Removed(entity));
+        }
+        self.sorted.clear();
+        self.entities.clear();
+        self.current_parent.clear();
+        self.external_parents.clear();
+        self.spare_children
+            .extend(self.children.drain().map(|(_, mut children)| {
+                children.clear();
+                children
+            }));
+        self.depths.clear();
+        self.old_depths.clear();
+        self.depth_changed.clear();
+        self.descendant_counts.clear();
+        self.moved.clear();
+        self.frame_delta = FrameDelta::default();
+        self.emptied.clear();
+        self.modified.clear();
+        self.inserted.clear();
+        self.removed.clear();
+        self.dirty.clear();
+        self.relinked.clear();
+        self.scratch_set.clear();
+        self.sorted_before.clear();
+        self.index_before.clear();
+        self.cycles.clear();
+        self.deferred.clear();
+        self.cyclic.clear();
+        self.batches.clear();
+        self.rejected.clear();
+        self.detaching.clear();
+        self.coalesced.clear();
+    }
+
+    /// Release memory that is no longer needed by the internal structures of the hierarchy.
+    pub fn shrink_to_fit(&mut self) {
+        self.sorted.shrink_to_fit();
+        self.sorted_before.clear();
+        self.sorted_before.shrink_to_fit();
+        self.index_before.clear();
+        self.index_before.shrink_to_fit();
+        self.entities.shrink_to_fit();
+        self.current_parent.shrink_to_fit();
+        self.external_parents.shrink_to_fit();
+        self.children.shrink_to_fit();
+        for children in self.children.values_mut() {
+            children.shrink_to_fit();
+        }
+        self.spare_children = Vec::new();
+        self.depths.shrink_to_fit();
+        self.old_depths.shrink_to_fit();
+        self.descendant_counts.shrink_to_fit();
+    }
+
+    /// Get an estimate of the memory used by the hierarchy, in bytes.
+    ///
+    /// This sums the allocated capacity of the sorted list, the parent-child links and the index
+    /// maps, and ignores the overhead of the hash maps and the smaller caches. It is meant as a
+    /// gauge to spot runaway growth, not as an exact number.
+    pub fn memory_usage(&self) -> usize {
+        let entity = std::mem::size_of::<Entity>();
+        let children = self
+            .children
+            .values()
+            .map(|children| children.capacity() * entity)
+            .sum::<usize>();
+        self.sorted.capacity() * entity
+            + self.entities.capacity() * std::mem::size_of::<Option<usize>>()
+            + self.children.capacity() * (entity + std::mem::size_of::<Vec<Entity>>())
+            + children
+            + self.current_parent.capacity() * std::mem::size_of::<Option<(Entity, Entity)>>()
+            + self.external_parents.capacity() * entity
+    }
+
+    /// Returns `true` if every entity in `all` is after its parent, and the internal index of
+    /// every entity matches its position in `all`.
+    pub fn is_topologically_sorted(&self) -> bool {
+        self.sorted.iter().enumerate().all(|(index, entity)| {
+            if self.tombstones.contains(entity) {
+                return true;
+            }
+            self.index_of(*entity) == Some(index)
+                && self
+                    .current_parent
+                    .get(entity)
+                    .and_then(|parent| self.index_of(*parent))
+                    .map(|parent_index| parent_index < index)
+                    .unwrap_or(true)
+        })
+    }
+
+    /// Throw away the sorted order and compute it again from the parent-child links.
+    ///
+    /// Each tree is laid out depth first, following the order of `children`, and the trees keep
+    /// the order their roots had before, see `roots`. Lazily removed entities are dropped, see
+    /// `compact`.
+    pub fn rebuild_sorted(&mut self) {
+        let mut roots = Vec::new();
+        let mut visited = HashSet::new();
+        let parents = self
+            .iter_sorted()
+            .chain(self.current_parent.keys().cloned())
+            .map(|entity| self.current_parent[&entity])
+            .collect::<Vec<_>>();
+        for parent in parents {
+            if !self.current_parent.contains_key(&parent) && visited.insert(parent) {
+                roots.push(parent);
+            }
+        }
+
+        self.sorted.clear();
+        self.tombstones.clear();
+        self.entities.clear();
+        let mut stack = Vec::new();
+        for root in roots {
+            stack.extend(self.children(root).iter().rev().cloned());
+            while let Some(entity) = stack.pop() {
+                self.entities.insert(entity.id(), self.sorted.len());
+                self.sorted.push(entity);
+                stack.extend(self.children(entity).iter().rev().cloned());
+            }
+        }
+    }
+
+    /// Append `child` as a leaf at the end of `parent`'s children, bypassing the component event
+    /// reader.
+    ///
+    /// This is a fast path for adding many leaves under a known parent, and will send a `Modified`
+    /// event for `child`. The caller must make sure that `child` is not already part of the
+    /// hierarchy and has no children of its own.
+    ///
+    /// Note: The caller must also set the `Parent` component on `child`, pointing to `parent`,
+    /// or later calls to `maintain` will not be consistent with the hierarchy.
+    pub fn append_leaf(&mut self, child: Entity, parent: Entity) {
+        self.compact();
+        debug_assert!(!self.entities.contains_key(&child.id()));
+        debug_assert!(!self.children.contains_key(&child));
+
+        self.push_leaf(child, parent);
+        self.emit(HierarchyEvent::Modified(child));
+    }
+
+    /// Add `child` at the end of the sorted list and of `parent`'s children, without sending
+    /// any events.
+    fn push_leaf(&mut self, child: Entity, parent: Entity) {
+        // parents are always before their children in the sorted list, so the end is valid
+        self.entities.insert(child.id(), self.sorted.len());
+        self.sorted.push(child);
+        self.children.entry(parent).or_default().push(child);
+        self.current_parent.insert(child, parent);
+        if !self.current_parent.contains_key(&parent) {
+            self.external_parents.insert(parent);
+        }
+        let depth = self.depths.get(&parent).map(|depth| depth + 1).unwrap_or(0);
+        self.depths.insert(child, depth);
+        self.add_descendants(parent, 1);
+    }
+
+    /// Remove the `Parent` component of `entity`, and make it the root of its own tree.
+    ///
+    /// Unlike just removing the component, which removes the whole subtree of `entity` from the
+    /// hierarchy, the children of `entity` stay in the hierarchy below it. The hierarchy is only
+    /// updated on the next `maintain`, which sends a `Removed` event for `entity` and `Modified`
+    /// events for its recursive children. If `entity` is deleted before that, its subtree is
+    /// removed as usual.
+    ///
+    /// This needs the hierarchy as well as the storage, because the component event for the
+    /// removal looks the same as any other removal, so the hierarchy has to remember that the
+    /// subtree should be kept. A system that only reads the hierarchy can remove the component
+    /// instead, which removes the whole subtree.
+    ///
+    /// Returns the removed component, if there was one.
+    pub fn detach(&mut self, entity: Entity, parents: &mut WriteStorage<P>) -> Option<P>
+    where
+        P: Component,
+    {
+        self.detaching.insert(entity);
+        parents.remove(entity)
+    }
+
+    /// Start holding back the modification events for `entity` and its subtree, until
+    /// `end_batch` is called for it.
+    ///
+    /// Entities that join the subtree during the batch are included as well.
+    pub fn begin_batch(&mut self, entity: Entity) {
+        let members = self.subtree_set(entity);
+        self.batches.insert(
+            entity,
+            Batch {
+                members,
+                suppressed: false,
+            },
+        );
+    }
+
+    /// Stop holding back the modification events for `entity` and its subtree, see
+    /// `begin_batch`.
+    ///
+    /// If any events were held back, they are replaced by a single `Modified(entity)` event.
+    pub fn end_batch(&mut self, entity: Entity) {
+        if let Some(batch) = self.batches.remove(&entity) {
+            if batch.suppressed {
+                self.emit(HierarchyEvent::Modified(entity));
+            }
+        }
+    }
+
+    /// Get the batch that holds back events for `entity`, if any.
+    fn batch_for(&mut self, entity: Entity) -> Option<&mut Batch> {
+        let mut current = entity;
+        let mut root = None;
+        loop {
+            if self.batches.contains_key(&current) {
+                root = Some(current);
+                break;
+            }
+            match self.current_parent.get(&current) {
+                Some(parent) => current = *parent,
+                None => break,
+            }
+        }
+        match root {
+            Some(root) => self.batches.get_mut(&root),
+            // entities that already left the hierarchy can only be found by their old subtree
+            None => self
+                .batches
+                .values_mut()
+                .find(|batch| batch.members.contains(entity.id())),
+        }
+    }
+
+    /// Send an event on the internal `EventChannel`.
+    ///
+    /// Events are dropped when no reader is registered, so a hierarchy that is never tracked does
+    /// not pay for them.
+    fn emit(&mut self, event: HierarchyEvent) {
+        if self.coalescing {
+            if let HierarchyEvent::Modified(entity) = event {
+                if self.coalesced.add(entity.id()) {
+                    return;
+                }
+            }
+        }
+        if !self.batches.is_empty() {
+            if let Some(batch) = self.batch_for(event.entity()) {
+                batch.suppressed = true;
+                return;
+            }
+        }
+        if self.history_capacity > 0 {
+            if self.history.len() == self.history_capacity {
+                self.history.pop_front();
+            }
+            self.history.push_back((self.tick, event));
+        }
+        write_event(&mut self.changed, event);
+    }
+
+    /// Remove `root` and all recursive children up to `max_depth` levels below it from the
+    /// hierarchy, bypassing the component event reader.
+    ///
+    /// The children just beyond `max_depth` are detached from their parents, and become roots of
+    /// their own trees. Will send `Removed` events for the removed entities, which are also
+    /// returned in sorted order, `Modified` events for the detached entities and their
+    /// recursive children, and a `BecameLeaf` event if the parent of `root` has no children left.
+    ///
+    /// Note: The caller must also update the `Parent` components of both the removed and the
+    /// detached entities, or later calls to `maintain` will not be consistent with the hierarchy.
+    pub fn remove_subtree_to_depth(&mut self, root: Entity, max_depth: usize) -> Vec<Entity> {
+        self.compact();
+        let mut removed = HashSet::new();
+        let mut detached = HashSet::new();
+        removed.insert(root);
+        let mut level = vec![root];
+        for depth in 0..=max_depth {
+            let mut next = Vec::new();
+            for entity in &level {
+                for child in self.children(*entity) {
+                    if depth < max_depth {
+                        removed.insert(*child);
+                        next.push(*child);
+                    } else {
+                        detached.insert(*child);
+                    }
+                }
+            }
+            level = next;
+        }
+
+        let mut emptied = None;
+        if let Some(parent) = self.current_parent.get(&root).cloned() {
+            if let Some(children) = self.children.get_mut(&parent) {
+                children.retain(|e| *e != root);
+                if children.is_empty() {
+                    emptied = Some(parent);
+                }
+            }
+        }
+        for entity in &detached {
+            self.current_parent.remove(entity);
+            self.entities.remove(&entity.id());
+            if self.children.contains_key(entity) {
+                self.external_parents.insert(*entity);
+            }
+        }
+        for entity in &removed {
+            self.current_parent.remove(entity);
+            self.children.remove(entity);
+            self.external_parents.remove(entity);
+        }
+
+        let mut removed_sorted = Vec::new();
+        let mut detached_sorted = Vec::new();
+        let mut notify = Vec::new();
+        let mut modified = detached.clone();
+        let mut min_index = usize::MAX;
+        for (i, entity) in self.sorted.iter().enumerate() {
+            if removed.contains(entity) {
+                removed_sorted.push(*entity);
+                min_index = min_index.min(i);
+            } else if detached.contains(entity) {
+                detached_sorted.push(*entity);
+                min_index = min_index.min(i);
+            } else if self
+                .current_parent
+                .get(entity)
+                .map(|parent| modified.contains(parent))
+                .unwrap_or(false)
+            {
+                modified.insert(*entity);
+                notify.push(*entity);
+            }
+        }
+        self.sorted
+            .retain(|entity| !removed.contains(entity) && !detached.contains(entity));
+        for entity in &removed_sorted {
+            self.entities.remove(&entity.id());
+        }
+        for i in min_index.min(self.sorted.len())..self.sorted.len() {
+            self.entities.insert(self.sorted[i].id(), i);
+        }
+        for entity in notify.into_iter().chain(detached_sorted) {
+            self.emit(HierarchyEvent::Modified(entity));
+        }
+        for entity in &removed_sorted {
+            self.emit(HierarchyEvent::Removed(*entity));
+        }
+        if let Some(parent) = emptied {
+            self.emit(HierarchyEvent::BecameLeaf(parent));
+        }
+        self.relinked.clear();
+        self.update_depths();
+        self.rebuild_descendant_counts();
+        removed_sorted
+    }
+
+    /// Maintain the hierarchy, usually only called by `HierarchySystem`.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the parent links contain a cycle once all changes are applied, unless a handler
+    /// was set with `set_cycle_handler`. Use `try_maintain` to handle cycles as an error instead.
+    /// A cycle is only reported in the frame it is created, see `set_cycle_handler`.
+    pub fn maintain(&mut self, data: ParentData<P>)
+    where
+        P: Component + Parent,
+        P::Storage: Tracked,
+    {
+        if let Err(err) = self.try_maintain(data) {
+            if self.cycle_handler.is_none() {
+                panic!("{}", err);
+            }
+        }
+    }
+
+    /// Maintain the hierarchy, only following the parent links for which `accept` returns `true`.
+    ///
+    /// `accept` is called with each child and the parent from its `Parent` component. A rejected
+    /// child is treated as if it had no `Parent` component, which makes it the root of its own
+    /// subtree: a `Removed` event is sent for it, and its children stay in the hierarchy. Since
+    /// the result of `accept` can change between frames, every `Parent` component is checked on
+    /// each call, and a rejected child joins the hierarchy again once it is accepted.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if a parent link would create a cycle, like `maintain`.
+    pub fn maintain_accepting<F>(&mut self, data: ParentData<P>, accept: F)
+    where
+        P: Component + Parent,
+        P::Storage: Tracked,
+        F: Fn(Entity, Entity) -> bool,
+    {
+        if let Err(err) = self.maintain_with(data, Some(&accept)) {
+            if self.cycle_handler.is_none() {
+                panic!("{}", err);
+            }
+        }
+    }
+
+    /// Maintain the hierarchy, reporting parent links that would create a cycle as an error.
+    ///
+    /// The offending links are ignored, and the rest of the changes are still applied, so the
+    /// hierarchy stays consistent. If there are several cycles, only the first one is reported.
+    /// Changes are checked together, so links that are only cyclic halfway through applying them,
+    /// like a parent and child swapping places, are not reported.
+    pub fn try_maintain(&mut self, data: ParentData<P>) -> Result<(), HierarchyError>
+    where
+        P: Component + Parent,
+        P::Storage: Tracked,
+    {
+        self.maintain_with(data, None)
+    }
+
+    fn maintain_with(
+        &mut self,
+        data: ParentData<P>,
+        accept: Option<&dyn Fn(Entity, Entity) -> bool>,
+    ) -> Result<(), HierarchyError>
+    where
+        P: Component + Parent,
+        P::Storage: Tracked,
+    {
+        let ParentData {
+            entities, parents, ..
+        } = data;
+
+        // Maintain tracking
+        self.tick += 1;
+        self.dirty.clear();
+        self.relinked.clear();
+        self.depth_changed.clear();
+        self.moved.clear();
+        self.frame_delta.inserted.clear();
+        self.frame_delta.reparented.clear();
+        self.frame_delta.removed.clear();
+        self.cycles.clear();
+        self.modified.clear();
+        self.inserted.clear();
+        self.removed.clear();
+        self.coalesced.clear();
+        self.coalescing = self.coalesce_events;
+
+        read_component_events(
+            parents.channel().read(&mut self.reader_id),
+            &mut self.modified,
+            &mut self.inserted,
+            &mut self.removed,
+        );
+
+        // retry the links that closed a cycle in an earlier frame, while they are unchanged
+        let cyclic = std::mem::take(&mut self.cyclic);
+        for (entity, parent_entity) in &cyclic {
+            let unchanged = !self.removed.contains(entity.id())
+                && entities.is_alive(*entity)
+                && parents.get(*entity).map(|parent| parent.parent_entity())
+                    == Some(*parent_entity);
+            if !unchanged {
+                continue;
+            }
+            if self.is_ancestor(*entity, *parent_entity) {
+                // may be resolved by the other links of this frame
+                self.deferred.push((*entity, *parent_entity));
+            } else {
+                self.modified.add(entity.id());
+            }
+        }
+
+        // check every parent link, to pick up changes in the result of `accept`
+        let mut reject = Vec::new();
+        if let Some(accept) = accept {
+            self.rejected
+                .retain(|entity| parents.get(*entity).is_some());
+            for (entity, parent) in (&*entities, &parents).join() {
+                let parent_entity = parent.parent_entity();
+                if accept(entity, parent_entity) {
+                    if self.rejected.remove(&entity) && entities.is_alive(parent_entity) {
+                        self.inserted.add(entity.id());
+                    }
+                } else {
+                    self.rejected.insert(entity);
+                    self.inserted.remove(entity.id());
+                    self.modified.remove(entity.id());
+                    if self.current_parent.contains_key(&entity) {
+                        reject.push(entity);
+                    }
+                }
+            }
+        }
+
+        // process removed parent components
+        self.scratch_set.clear();
+        for id in (&self.removed).iter() {
+            if let Some(index) = self.entities.get(&id) {
+                let entity = self.sorted[*index];
+                if self.detaching.contains(&entity) && entities.is_alive(entity) {
+                    reject.push(entity);
+                } else {
+                    self.scratch_set.insert(entity);
+                }
+            }
+        }
+        self.detaching.clear();
+        // handle parents that have been removed which do not have a Parent themselves
+        for entity in &self.external_parents {
+            if !entities.is_alive(*entity) {
+                self.scratch_set.insert(*entity);
+            }
+        }
+
+        // keep the previous sorted order to find the entities that moved, reusing the buffer, but
+        // only if this frame changes the structure
+        let track_moves = !self.modified.is_empty()
+            || !self.inserted.is_empty()
+            || !self.scratch_set.is_empty()
+            || !reject.is_empty();
+        if track_moves {
+            self.sorted_before.clone_from(&self.sorted);
+        }
+
+        // do removal
+        if !self.scratch_set.is_empty() {
+            let mut i = 0;
+            let mut min_index = usize::MAX;
+            while i < self.sorted.len() {
+                let entity = self.sorted[i];
+                if self.tombstones.contains(&entity) {
+                    i += 1;
+                    continue;
+                }
+                let remove = self.scratch_set.contains(&entity)
+                    || self
+                        .current_parent
+                        .get(&entity)
+                        .map(|parent_entity| self.scratch_set.contains(parent_entity))
+                        .unwrap_or(false);
+                if remove {
+                    self.frame_delta.removed.push(entity);
+                    if let Some(parent_entity) = self.current_parent.get(&entity).cloned() {
+                        if !self.scratch_set.contains(&parent_entity) {
+                            let size = self.descendant_count(entity) + 1;
+                            self.remove_descendants(parent_entity, size);
+                        }
+                    }
+                    self.scratch_set.insert(entity);
+                    if self.lazy_removal {
+                        self.tombstones.insert(entity);
+                        i += 1;
+                    } else {
+                        min_index = min_index.min(i);
+                        self.sorted.remove(i);
+                    }
+                    if let Some(parent_entity) = self.current_parent.get(&entity) {
+                        self.dirty.add(parent_entity.id());
+                    }
+                    if let Some(children) = self
+                        .current_parent
+                        .get(&entity)
+                        .cloned()
+                        .and_then(|parent_entity| self.children.get_mut(&parent_entity))
+                    {
+                        if let Some(pos) = children.iter().position(|e| *e == entity) {
+                            children.swap_remove(pos);
+                        }
+                    }
+                    if let Some(parent_entity) = self.current_parent.get(&entity) {
+                        if !self.scratch_set.contains(parent_entity) {
+                            self.emptied.push(*parent_entity);
+                        }
+                    }
+                    self.current_parent.remove(&entity);
+                    if let Some(mut children) = self.children.remove(&entity) {
+                        children.clear();
+                        self.spare_children.push(children);
+                    }
+                    self.auto_sorted.remove(&entity);
+                    self.entities.remove(&entity.id());
+                } else {
+                    i += 1;
+                }
+            }
+            for i in min_index.min(self.sorted.len())..self.sorted.len() {
+                self.entities.insert(self.sorted[i].id(), i);
+            }
+            let scratch_set = std::mem::take(&mut self.scratch_set);
+            for entity in &scratch_set {
+                self.emit(HierarchyEvent::Removed(*entity));
+                self.external_parents.remove(entity);
+                self.descendant_counts.remove(entity);
+            }
+            self.scratch_set = scratch_set;
+        }
+
+        // lazily removed entities are compacted before any structural change that needs the
+        // sorted list to be contiguous
+        if !self.inserted.is_empty() || !self.modified.is_empty() {
+            self.compact();
+        }
+
+        // insert new components in hierarchy
+        self.scratch_set.clear();
+        // detach rejected and detached entities, once their removed ancestors are gone
+        for entity in reject {
+            if self.current_parent.contains_key(&entity) {
+                self.detach_entity(entity);
+            }
+        }
+        let inserted = std::mem::take(&mut self.inserted);
+        for (entity, _, parent) in (&*entities, &inserted, &parents).join() {
+            self.link(entity, parent.parent_entity());
+        }
+        self.inserted = inserted;
+
+        let modified = std::mem::take(&mut self.modified);
+        for (entity, _, parent) in (&*entities, &modified, &parents).join() {
+            self.link(entity, parent.parent_entity());
+        }
+        self.modified = modified;
+
+        // links that looked like cycles can become valid once the other links of this frame are
+        // applied, e.g. when two entities swap places, so retry them until nothing changes
+        let mut pending = std::mem::take(&mut self.deferred);
+        while !pending.is_empty() {
+            let count = pending.len();
+            for (entity, parent_entity) in pending.drain(..) {
+                self.link(entity, parent_entity);
+            }
+            std::mem::swap(&mut pending, &mut self.deferred);
+            if pending.len() == count {
+                break;
+            }
+        }
+        // whatever is left forms a cycle in the final parent links, and is retried next frame
+        for (entity, parent_entity) in pending.drain(..) {
+            if !cyclic.contains(&(entity, parent_entity)) {
+                self.reject_cycle(entity, parent_entity);
+            }
+            self.cyclic.insert((entity, parent_entity));
+        }
+        self.deferred = pending;
+
+        if !self.scratch_set.is_empty() {
+            for i in 0..self.sorted.len() {
+                let entity = self.sorted[i];
+                let notify = self.scratch_set.contains(&entity)
+                    || self
+                        .current_parent
+                        .get(&entity)
+                        .map(|parent_entity| self.scratch_set.contains(parent_entity))
+                        .unwrap_or(false);
+                if notify {
+                    self.scratch_set.insert(entity);
+                    self.dirty.add(entity.id());
+                    self.emit(HierarchyEvent::Modified(entity));
+                }
+            }
+        }
+
+        if let Some(ref comparator) = self.child_comparator {
+            for parent in &self.auto_sorted {
+                if let Some(children) = self.children.get_mut(parent) {
+                    children.sort_by(|a, b| comparator(a, b));
+                }
+            }
+        }
+
+        // notify parents that lost all of their children
+        self.scratch_set.clear();
+        let mut emptied = std::mem::take(&mut self.emptied);
+        for entity in emptied.drain(..) {
+            if self.children(entity).is_empty() && self.scratch_set.insert(entity) {
+                self.emit(HierarchyEvent::BecameLeaf(entity));
+            }
+        }
+        self.emptied = emptied;
+
+        self.scratch_set.clear();
+        for entity in &self.external_parents {
+            if !self.children.contains_key(entity) {
+                self.scratch_set.insert(*entity);
+            }
+        }
+        for entity in &self.scratch_set {
+            self.external_parents.remove(entity);
+        }
+
+        if !self.dirty.is_empty() {
+            self.update_depths();
+        }
+
+        if track_moves {
+            self.index_before.clear();
+            for (index, entity) in self.sorted_before.iter().enumerate() {
+                self.index_before.insert(entity.id(), index);
+            }
+            for (index, entity) in self.sorted.iter().enumerate() {
+                let moved = self
+                    .index_before
+                    .get(&entity.id())
+                    .map(|i| *i != index && self.sorted_before[*i] == *entity)
+                    .unwrap_or(false);
+                if moved && !self.tombstones.contains(entity) {
+                    self.moved.push(*entity);
+                }
+            }
+        }
+
+        self.coalescing = false;
+        self.compact();
+
+        #[cfg(debug_assertions)]
+        self.check_invariants();
+
+        match self.cycles.drain(..).next() {
+            Some(cycle) => Err(HierarchyError::Cycle(cycle)),
+            None => Ok(()),
+        }
+    }
+
+    /// Panic if the internal state of the hierarchy is inconsistent.
+    ///
+    /// This runs after every `maintain` in debug builds, to catch bugs where the sorted list, the
+    /// index map and the parent-child links drift apart.
+    #[cfg(debug_assertions)]
+    fn check_invariants(&self) {
+        let mut count = 0;
+        for (index, entity) in self.sorted.iter().enumerate() {
+            if self.tombstones.contains(entity) {
+                continue;
+            }
+            count += 1;
+            assert_eq!(
+                self.entities.get(&entity.id()),
+                Some(&index),
+                "hierarchy index of {:?} does not match its position in the sorted list",
+                entity
+            );
+            let parent = match self.current_parent.get(entity) {
+                Some(parent) => *parent,
+                None => panic!("{:?} is in the sorted list, but has no parent", entity),
+            };
+            if let Some(parent_index) = self.index_of(parent) {
+                assert!(
+                    parent_index < index,
+                    "parent {:?} is sorted after its child {:?}",
+                    parent,
+                    entity
+                );
+            }
+            assert!(
+                self.children(parent).contains(entity),
+                "{:?} is missing from the children of its parent {:?}",
+                entity,
+                parent
+            );
+        }
+        assert_eq!(
+            self.entities.len(),
+            count,
+            "hierarchy index map contains entities that are not in the sorted list"
+        );
+        assert_eq!(
+            self.current_parent.len(),
+            count,
+            "hierarchy has parent links for entities that are not in the sorted list"
+        );
+        for (parent, children) in &self.children {
+            for child in children {
+                assert_eq!(
+                    self.current_parent.get(child),
+                    Some(parent),
+                    "{:?} is a child of {:?}, but has a different parent",
+                    child,
+                    parent
+                );
+            }
+        }
+    }
+
+    /// Add `amount` to the cached descendant count of `entity` and all of its ancestors.
+    fn add_descendants(&mut self, entity: Entity, amount: usize) {
+        let mut current = Some(entity);
+        while let Some(entity) = current {
+            *self.descendant_counts.entry(entity).or_insert(0) += amount;
+            current = self.current_parent.get(&entity).cloned();
+        }
+    }
+
+    /// Subtract `amount` from the cached descendant count of `entity` and all of its ancestors.
+    fn remove_descendants(&mut self, entity: Entity, amount: usize) {
+        let mut current = Some(entity);
+        while let Some(entity) = current {
+            if let Some(count) = self.descendant_counts.get_mut(&entity) {
+                *count -= amount;
+                if *count == 0 {
+                    self.descendant_counts.remove(&entity);
+                }
+            }
+            current = self.current_parent.get(&entity).cloned();
+        }
+    }
+
+    /// Recompute the cached descendant count of every entity from scratch.
+    fn rebuild_descendant_counts(&mut self) {
+        self.descendant_counts.clear();
+        let entities = self.iter_sorted().collect::<Vec<_>>();
+        for entity in entities {
+            let parent = self.current_parent[&entity];
+            self.add_descendants(parent, 1);
+        }
+    }
+
+    /// Recompute the cached depth of every entity in one pass over the sorted list.
+    fn update_depths(&mut self) {
+        std::mem::swap(&mut self.depths, &mut self.old_depths);
+        self.depths.clear();
+        self.depth_changed.clear();
+        let tombstones = &self.tombstones;
+        for entity in self.sorted.iter().filter(|e| !tombstones.contains(e)) {
+            let depth = self
+                .current_parent
+                .get(entity)
+                .and_then(|parent| self.depths.get(parent))
+                .map(|depth| depth + 1)
+                .unwrap_or(0);
+            self.depths.insert(*entity, depth);
+            if !self.relinked.contains(entity)
+                && self
+                    .old_depths
+                    .get(entity)
+                    .map(|old_depth| *old_depth != depth)
+                    .unwrap_or(false)
+            {
+                self.depth_changed.push(*entity);
+            }
+        }
+    }
+
+    /// Link `entity` to `parent_entity`, inserting it in the hierarchy if needed.
+    fn link(&mut self, entity: Entity, parent_entity: Entity) {
+        match self.current_parent.get(&entity).cloned() {
+            // the parent entity was not changed, or was already added through `append_leaf`
+            Some(old_parent) if old_parent == parent_entity => {}
+            _ if parent_entity == entity => self.emit(HierarchyEvent::Invalid(entity)),
+            // retried after the other links of the frame, see `maintain_with`
+            _ if self.is_ancestor(entity, parent_entity) => {
+                self.deferred.push((entity, parent_entity))
+            }
+            Some(old_parent) => self.reparent_entity(entity, old_parent, parent_entity),
+            None => self.insert_entity(entity, parent_entity),
+        }
+    }
+
+    /// Record that linking `entity` to `parent_entity` would create a cycle, and notify the cycle
+    /// handler.
+    fn reject_cycle(&mut self, entity: Entity, parent_entity: Entity) {
+        let mut cycle = vec![entity];
+        let mut current = parent_entity;
+        while current != entity {
+            cycle.push(current);
+            current = self.current_parent[&current];
+        }
+        if let Some(handler) = self.cycle_handler.as_mut() {
+            handler(entity, parent_entity);
+        }
+        self.cycles.push(cycle);
+    }
+
+    fn insert_entity(&mut self, entity: Entity, parent_entity: Entity) {
+        self.relinked.insert(entity);
+        self.frame_delta.inserted.push(entity);
+        // if we insert a parent component on an entity that have children, we need to make
+        // sure the parent is inserted before the children in the sorted list
+        let insert_index = self
+            .children
+            .get(&entity)
+            .and_then(|children| {
+                children
+                    .iter()
+                    .map(|child_entity| self.entities.get(&child_entity.id()).unwrap())
+                    .min()
+                    .cloned()
+            })
+            .unwrap_or(self.sorted.len());
+        self.entities.insert(entity.id(), insert_index);
+        if insert_index >= self.sorted.len() {
+            self.sorted.push(entity);
+        } else {
+            self.sorted.insert(insert_index, entity);
+            for i in insert_index..self.sorted.len() {
+                self.entities.insert(self.sorted[i].id(), i);
+            }
+        }
+        self.move_parents_before(entity, parent_entity);
+
+        {
+            let spare_children = &mut self.spare_children;
+            let children = self
+                .children
+                .entry(parent_entity)
+                .or_insert_with(|| spare_children.pop().unwrap_or_default());
+            children.push(entity);
+        }
+
+        self.current_parent.insert(entity, parent_entity);
+        let size = self.descendant_count(entity) + 1;
+        self.add_descendants(parent_entity, size);
+        self.scratch_set.insert(entity);
+        if !self.current_parent.contains_key(&parent_entity) {
+            self.external_parents.insert(parent_entity);
+        }
+        self.external_parents.remove(&entity);
+    }
+
+    /// Move `parent_entity` and its ancestors in sorted before `entity`, if they are after it.
+    fn move_parents_before(&mut self, entity: Entity, parent_entity: Entity) {
+        let entity_index = self.entities.get(&entity.id()).cloned().unwrap();
+        if let Some(parent_index) = self.entities.get(&parent_entity.id()).cloned() {
+            let mut offset = 0;
+            let mut process_index = parent_index;
+            while process_index > entity_index {
+                let move_entity = self.sorted.remove(process_index);
+                self.sorted.insert(entity_index, move_entity);
+                offset += 1;
+                process_index = self
+                    .current_parent
+                    .get(&move_entity)
+                    .and_then(|p_entity| self.entities.get(&p_entity.id()))
+                    .map(|p_index| {
+                        if *p_index >= entity_index {
+                            p_index + offset
+                        } else {
+                            *p_index
+                        }
+                    })
+                    .unwrap_or(0);
+            }
+
+            // fix indexes
+            if parent_index > entity_index {
+                for i in entity_index..=parent_index {
+                    self.entities.insert(self.sorted[i].id(), i);
+                }
+            }
+        }
+    }
+
+    /// Unlink `entity` from its parent, keeping its subtree in the hierarchy below it.
+    ///
+    /// The children of `entity` are added to `scratch_set`, so they and their recursive children
+    /// are notified of their new depth.
+    fn detach_entity(&mut self, entity: Entity) {
+        self.compact();
+        let parent_entity = self.current_parent[&entity];
+        let index = self.entities.remove(&entity.id()).unwrap();
+        self.sorted.remove(index);
+        for i in index..self.sorted.len() {
+            self.entities.insert(self.sorted[i].id(), i);
+        }
+        if let Some(children) = self.children.get_mut(&parent_entity) {
+            children.retain(|e| *e != entity);
+        }
+        let size = self.descendant_count(entity) + 1;
+        self.remove_descendants(parent_entity, size);
+        self.current_parent.remove(&entity);
+        self.depths.remove(&entity);
+        self.dirty.add(parent_entity.id());
+        self.emptied.push(parent_entity);
+        self.frame_delta.removed.push(entity);
+        if self.children.contains_key(&entity) {
+            self.external_parents.insert(entity);
+        }
+        self.scratch_set.extend(self.children(entity).to_vec());
+        self.emit(HierarchyEvent::Removed(entity));
+    }
+
+    fn reparent_entity(&mut self, entity: Entity, old_parent: Entity, parent_entity: Entity) {
+        self.relinked.insert(entity);
+        self.frame_delta
+            .reparented
+            .push((entity, old_parent, parent_entity));
+        self.emit(HierarchyEvent::Reparented {
+            entity,
+            old_parent,
+            new_parent: parent_entity,
+        });
+        self.dirty.add(old_parent.id());
+        let mut old_rank = None;
+        // remove entity from old parents children
+        if let Some(children) = self.children.get_mut(&old_parent) {
+            if let Some(pos) = children.iter().position(|e| *e == entity) {
+                children.remove(pos);
+                old_rank = Some(pos);
+            }
+        }
+        self.emptied.push(old_parent);
+        let size = self.descendant_count(entity) + 1;
+        self.remove_descendants(old_parent, size);
+
+        // insert in new parents children
+        {
+            let spare_children = &mut self.spare_children;
+            let children = self
+                .children
+                .entry(parent_entity)
+                .or_insert_with(|| spare_children.pop().unwrap_or_default());
+            let index = match self.reparent_placement {
+                ReparentPlacement::End => children.len(),
+                ReparentPlacement::Front => 0,
+                ReparentPlacement::PreserveRank => old_rank
+                    .map(|rank| rank.min(children.len()))
+                    .unwrap_or(children.len()),
+            };
+            children.insert(index, entity);
+        }
+
+        self.move_parents_before(entity, parent_entity);
+
+        self.current_parent.insert(entity, parent_entity);
+        self.add_descendants(parent_entity, size);
+        self.scratch_set.insert(entity);
+
+        if !self.current_parent.contains_key(&parent_entity) {
+            self.external_parents.insert(parent_entity);
+        }
+    }
+}
+
+/// Send `event` on `changed`, unless there is no reader to receive it.
+fn write_event(changed: &mut EventChannel<HierarchyEvent>, event: HierarchyEvent) {
+    if changed.would_write() {
+        changed.single_write(event);
+    }
+}
+
+/// Sort the events of a parent component storage into the `modified`, `inserted` and `removed`
+/// sets.
+fn read_component_events<'a, I>(
+    events: I,
+    modified: &mut BitSet,
+    inserted: &mut BitSet,
+    removed: &mut BitSet,
+) where
+    I: IntoIterator<Item = &'a ComponentEvent>,
+{
+    for event in events {
+        match event {
+            ComponentEvent::Modified(id) => {
+                modified.add(*id);
+            }
+            ComponentEvent::Inserted(id) => {
+                inserted.add(*id);
+            }
+            ComponentEvent::Removed(id) => {
+                removed.add(*id);
+            }
+        }
+    }
+}
+
+/// The immediate surroundings of an `Entity` in the hierarchy, see `Hierarchy::neighborhood`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Neighborhood {
+    /// The parent of the `Entity`, if it has one
+    pub parent: Option<Entity>,
+    /// The other children of the parent, in order
+    pub siblings: Vec<Entity>,
+    /// The direct children of the `Entity`, in order
+    pub children: Vec<Entity>,
+}
+
+/// Summary of the changes made to the hierarchy by a single `maintain`, see
+/// `Hierarchy::take_frame_delta`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FrameDelta {
+    /// Entities that were added to the hierarchy, in the order they were added
+    pub inserted: Vec<Entity>,
+    /// Entities that were moved to another parent, as `(entity, old_parent, new_parent)`
+    pub reparented: Vec<(Entity, Entity, Entity)>,
+    /// Entities that were removed from the hierarchy, with parents before their children.
+    /// Roots that were deleted are not included
+    pub removed: Vec<Entity>,
+}
+
+/// The parent-child links of a hierarchy at some point in time, see `Hierarchy::snapshot`.
+///
+/// With the `serde` feature enabled, snapshots can be serialized, and restored later with
+/// `Hierarchy::from_snapshot`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HierarchySnapshot {
+    /// `(child, parent)` links, sorted by child
+    parents: Vec<(SnapshotEntity, SnapshotEntity)>,
+    /// The children of each parent in order, sorted by parent
+    children: Vec<(SnapshotEntity, Vec<SnapshotEntity>)>,
+}
+
+/// An `Entity` in a `HierarchySnapshot`, stored by index and generation.
+#[derive(Clone, Copy, Debug, Hash, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct SnapshotEntity {
+    id: Index,
+    gen: i32,
+}
+
+impl From<Entity> for SnapshotEntity {
+    fn from(entity: Entity) -> Self {
+        SnapshotEntity {
+            id: entity.id(),
+            gen: entity.gen().id(),
+        }
+    }
+}
+
+/// Events held back for a subtree, see `Hierarchy::begin_batch`.
+struct Batch {
+    members: BitSet,
+    suppressed: bool,
+}
+
+/// Reader for the modification events of a single subtree, see `Hierarchy::subtree_reader`.
+///
+/// An event is considered to affect the subtree if its entity is in the subtree either when the
+/// reader is polled, or when it was last polled. This means entities that are removed from, or
+/// moved out of the subtree are still reported.
+pub struct SubtreeReader {
+    root: Entity,
+    members: BitSet,
+    reader_id: ReaderId<HierarchyEvent>,
+}
+
+impl SubtreeReader {
+    /// Get the root of the subtree this reader is for.
+    pub fn root(&self) -> Entity {
+        self.root
+    }
+
+    /// Get the events sent since the last poll that affect the subtree.
+    pub fn poll<P>(&mut self, hierarchy: &Hierarchy<P>) -> Vec<HierarchyEvent> {
+        let members = hierarchy.subtree_set(self.root);
+        let events = hierarchy
+            .changed()
+            .read(&mut self.reader_id)
+            .filter(|event| {
+                let id = event.entity().id();
+                members.contains(id) || self.members.contains(id)
+            })
+            .cloned()
+            .collect();
+        self.members = members;
+        events
+    }
+}
+
+/// Hash of a single parent-child link, see `Hierarchy::structural_hash`.
+fn link_hash(child: Entity, parent: Entity) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (child, parent).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Cursor for paging through a subtree with `Hierarchy::descendants_paged`.
+///
+/// A cursor should only be used with a single root, and is no longer valid once the hierarchy is
+/// maintained.
+#[derive(Clone, Debug, Default)]
+pub struct PageCursor {
+    started: bool,
+    frontier: VecDeque<Entity>,
+}
+
+impl PageCursor {
+    /// Create a new cursor, starting at the beginning of the subtree.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns `true` if every recursive child has been yielded.
+    pub fn is_done(&self) -> bool {
+        self.started && self.frontier.is_empty()
+    }
+}
+
+/// Iterator over the ancestors of an `Entity`, see `Hierarchy::ancestors`.
+pub struct AncestorsIterator<'a, P>
+where
+    P: 'a,
+{
+    hierarchy: &'a Hierarchy<P>,
+    current: Entity,
+}
+
+impl<'a, P> Iterator for AncestorsIterator<'a, P>
+where
+    P: 'a,
+{
+    type Item = Entity;
+
+    fn next(&mut self) -> Option<Entity> {
+        let parent = *self.hierarchy.current_parent.get(&self.current)?;
+        if !self.hierarchy.current_parent.contains_key(&parent) {
+            return None;
+        }
+        self.current = parent;
+        Some(parent)
+    }
+}
+
+pub struct SubHierarchyIterator<'a, P>
+where
+    P: 'a,
+{
+    current_index: usize,
+    end_index: usize,
+    hierarchy: &'a Hierarchy<P>,
+    entities: BitSet,
+}
+
+impl<'a, P> SubHierarchyIterator<'a, P>
+where
+    P: 'a,
+{
+    fn new(hierarchy: &'a Hierarchy<P>, root: Entity) -> Self {
+        let max = hierarchy.sorted.len();
+        let root_index = hierarchy
+            .children
+            .get(&root)
+            .map(|children| {
+                children
+                    .iter()
+                    .map(|c| hierarchy.entities.get(&c.id()).cloned().unwrap_or(max))
+                    .min()
+                    .unwrap_or(max)
+            })
+            .unwrap_or(max);
+        let mut iter = SubHierarchyIterator {
+            hierarchy,
+            current_index: root_index,
+            end_index: 0,
+            entities: BitSet::new(),
+        };
+        iter.process_entity(root);
+        if root_index != max {
+            iter.process_entity(hierarchy.sorted[root_index]);
+        }
+        iter
+    }
+
+    fn process_entity(&mut self, child: Entity) {
+        if let Some(children) = self.hierarchy.children.get(&child) {
+            for child in children {
+                self.entities.add(child.id());
+                if let Some(index) = self.hierarchy.entities.get(&child.id()) {
+                    if *index > self.end_index {
+                        self.end_index = *index;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a, P> Iterator for SubHierarchyIterator<'a, P>
+where
+    P: 'a,
+{
+    type Item = Entity;
+
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        if self.current_index >= self.hierarchy.sorted.len() || self.current_index > self.end_index
+        {
+            None
+        } else {
+            let entity = self.hierarchy.sorted[self.current_index];
+            let mut found_next = false;
+            while !found_next {
+                self.current_index += 1;
+                if self.current_index > self.end_index
+                    || self.current_index >= self.hierarchy.sorted.len()
+                {
+                    found_next = true;
+                } else if self
+                    .entities
+                    .contains(self.hierarchy.sorted[self.current_index].id())
+                {
+                    found_next = true;
+                    let current_index = self.current_index; // compiler fails to realise a usize is Copy, so we break it out
+                    self.process_entity(self.hierarchy.sorted[current_index]);
+                }
+            }
+            Some(entity)
+        }
+    }
+}
+
+/// Breadth-first iterator over the recursive children of an `Entity`, see `Hierarchy::bfs_iter`.
+pub struct BfsIterator<'a, P>
+where
+    P: 'a,
+{
+    hierarchy: &'a Hierarchy<P>,
+    queue: VecDeque<Entity>,
+}
+
+impl<'a, P> BfsIterator<'a, P>
+where
+    P: 'a,
+{
+    fn new(hierarchy: &'a Hierarchy<P>, root: Entity) -> Self {
+        BfsIterator {
+            hierarchy,
+            queue: hierarchy.children(root).iter().cloned().collect(),
+        }
+    }
+}
+
+impl<'a, P> Iterator for BfsIterator<'a, P>
+where
+    P: 'a,
+{
+    type Item = Entity;
+
+    fn next(&mut self) -> Option<Entity> {
+        let entity = self.queue.pop_front()?;
+        self.queue
+            .extend(self.hierarchy.children(entity).iter().cloned());
+        Some(entity)
+    }
+}
+
+/// Depth-first pre-order iterator over the recursive children of an `Entity`, see
+/// `Hierarchy::dfs_preorder_iter`.
+pub struct DfsPreorderIterator<'a, P>
+where
+    P: 'a,
+{
+    hierarchy: &'a Hierarchy<P>,
+    stack: Vec<Entity>,
+}
+
+impl<'a, P> DfsPreorderIterator<'a, P>
+where
+    P: 'a,
+{
+    fn new(hierarchy: &'a Hierarchy<P>, root: Entity) -> Self {
+        DfsPreorderIterator {
+            hierarchy,
+            stack: hierarchy.children(root).iter().rev().cloned().collect(),
+        }
+    }
+}
+
+impl<'a, P> Iterator for DfsPreorderIterator<'a, P>
+where
+    P: 'a,
+{
+    type Item = Entity;
+
+    fn next(&mut self) -> Option<Entity> {
+        let entity = self.stack.pop()?;
+        self.stack
+            .extend(self.hierarchy.children(entity).iter().rev().cloned());
+        Some(entity)
+    }
+}
+
+/// Depth-first post-order iterator over the recursive children of an `Entity`, see
+/// `Hierarchy::dfs_postorder_iter`.
+pub struct DfsPostorderIterator<'a, P>
+where
+    P: 'a,
+{
+    hierarchy: &'a Hierarchy<P>,
+    // entities with whether their children were already pushed
+    stack: Vec<(Entity, bool)>,
+}
+
+impl<'a, P> DfsPostorderIterator<'a, P>
+where
+    P: 'a,
+{
+    fn new(hierarchy: &'a Hierarchy<P>, root: Entity) -> Self {
+        DfsPostorderIterator {
+            hierarchy,
+            stack: hierarchy
+                .children(root)
+                .iter()
+                .rev()
+                .map(|child| (*child, false))
+                .collect(),
+        }
+    }
+}
+
+impl<'a, P> Iterator for DfsPostorderIterator<'a, P>
+where
+    P: 'a,
+{
+    type Item = Entity;
+
+    fn next(&mut self) -> Option<Entity> {
+        loop {
+            let (entity, expanded) = self.stack.pop()?;
+            if expanded {
+                return Some(entity);
+            }
+            self.stack.push((entity, true));
+            self.stack.extend(
+                self.hierarchy
+                    .children(entity)
+                    .iter()
+                    .rev()
+                    .map(|child| (*child, false)),
+            );
+        }
+    }
+}
+
+/// Bound for the parent component of your crate. Your `Parent` component usually just contains the
+/// `Entity` that's the parent you're linking to.
+///
+/// Note that the component should indicate that the `Entity` its added *has* a parent (the entity
+/// stored in your component).
+pub trait Parent {
+    /// Retrieves the parent `Entity`.
+    fn parent_entity(&self) -> Entity;
+}
+
+/// Ready-made parent component, for when you don't need your own `Parent` type.
+///
+/// The entity with this component *has* a parent, rather than *is* a parent.
+#[derive(Clone, Copy, Debug, Hash, Eq, Ord, PartialEq, PartialOrd)]
+pub struct SimpleParent {
+    /// The parent entity
+    pub entity: Entity,
+}
+
+impl Component for SimpleParent {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+impl Parent for SimpleParent {
+    fn parent_entity(&self) -> Entity {
+        self.entity
+    }
+}
+
+/// Make `parent` the parent of `child`, replacing any parent `child` already had.
+pub fn set_parent<'a>(
+    storage: &mut WriteStorage<'a, SimpleParent>,
+    child: Entity,
+    parent: Entity,
+) -> InsertResult<SimpleParent> {
+    storage.insert(child, SimpleParent { entity: parent })
+}
+
+/// Make `new_parent` the parent of `entity`, refusing moves that would create a cycle.
+///
+/// The new `Parent` component is created with `make`, and is only written if `new_parent` is
+/// neither `entity` itself nor one of its recursive children in `hierarchy`. The hierarchy picks
+/// up the change on the next `maintain`.
+pub fn reparent<'a, P, F>(
+    entity: Entity,
+    new_parent: Entity,
+    parents: &mut WriteStorage<'a, P>,
+    hierarchy: &Hierarchy<P>,
+    make: F,
+) -> Result<(), ReparentError>
+where
+    P: Component,
+    F: Fn(Entity) -> P,
+{
+    if entity == new_parent || hierarchy.is_ancestor(entity, new_parent) {
+        return Err(ReparentError::WouldCycle);
+    }
+    parents
+        .insert(entity, make(new_parent))
+        .map(|_| ())
+        .map_err(|_| ReparentError::DeadEntity)
+}
+
+/// Utility struct for the data needed by the `Hierarchy` maintain.
+#[derive(SystemData)]
+pub struct ParentData<'a, P>
+where
+    P: Component + Parent,
+    P::Storage: Tracked,
+{
+    entities: Entities<'a>,
+    parents: ReadStorage<'a, P>,
+}
+
+/// System for maintaining a `Hierarchy` resource.
+///
+/// ## Type parameters:
+///
+/// - `P`: Component type that provides `Parent` links for the maintained `Hierarchy`
+pub struct HierarchySystem<P> {
+    m: PhantomData<P>,
+}
+
+impl<P> HierarchySystem<P>
+where
+    P: Component + Parent + Send + Sync + 'static,
+    P::Storage: Tracked,
+{
+    pub fn new(world: &mut World) -> Self {
+        <Self as System<'_>>::SystemData::setup(world);
+        if !world.has_value::<Hierarchy<P>>() {
+            let hierarchy = {
+                let mut storage: WriteStorage<P> = SystemData::fetch(world);
+                Hierarchy::<P>::new(storage.register_reader())
+            };
+            world.insert(hierarchy);
+        }
+        HierarchySystem { m: PhantomData }
+    }
+}
+
+impl<'a, P> System<'a> for HierarchySystem<P>
+where
+    P: Component + Parent + Send + Sync + 'static,
+    P::Storage: Tracked,
+{
+    type SystemData = (ParentData<'a, P>, WriteExpect<'a, Hierarchy<P>>);
+
+    fn run(&mut self, (data, mut hierarchy): Self::SystemData) {
+        hierarchy.maintain(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{
+        reparent, set_parent, FrameDelta, Hierarchy, HierarchyError, HierarchyEvent,
+        HierarchySystem, MultiHierarchy, MultiHierarchySystem, MultiParent, Neighborhood,
+        PageCursor, Parent as PParent, ReparentError, ReparentPlacement, SimpleParent,
+        SubtreeReader,
+    };
+    use specs::prelude::{
+        BitSet, Builder, Component, DenseVecStorage, Entity, FlaggedStorage, ReaderId, RunNow,
+        World,
+    };
+    use specs::WorldExt;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    struct Parent {
+        entity: Entity,
+    }
+
+    impl Component for Parent {
+        type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+    }
+
+    impl PParent for Parent {
+        fn parent_entity(&self) -> Entity {
+            self.entity
+        }
+    }
+
+    fn delete_removals(world: &mut World, reader_id: &mut ReaderId<HierarchyEvent>) {
+        let mut remove = vec![];
+        for event in world.fetch::<Hierarchy<Parent>>().changed().read(reader_id) {
+            if let HierarchyEvent::Removed(entity) = *event {
+                remove.push(entity);
+            }
+        }
+        for entity in remove {
+            if world.delete_entity(entity).is_err() {
+                println!("Failed removed entity");
+            }
+        }
+    }
+
+    #[test]
+    fn parent_removed() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let mut reader_id = world.write_resource::<Hierarchy<Parent>>().track();
+
+        let e1 = world.create_entity().build();
+
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+
+        let e3 = world.create_entity().build();
+
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+
+        let e5 = world.create_entity().with(Parent { entity: e4 }).build();
+
+        system.run_now(&world);
+        delete_removals(&mut world, &mut reader_id);
+        world.maintain();
+
+        let _ = world.delete_entity(e1);
+        system.run_now(&world);
+        delete_removals(&mut world, &mut reader_id);
+        world.maintain();
+
+        assert!(!world.is_alive(e1));
+        assert!(!world.is_alive(e2));
+
+        let _ = world.delete_entity(e3);
+        system.run_now(&world);
+        delete_removals(&mut world, &mut reader_id);
+        world.maintain();
+
+        assert!(!world.is_alive(e3));
+        assert!(!world.is_alive(e4));
+        assert!(!world.is_alive(e5));
+
+        assert_eq!(0, world.read_resource::<Hierarchy<Parent>>().all().len());
+    }
+
+    #[test]
+    fn test_all_children_iter() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+
+        let e2 = world.create_entity().build();
+
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+
+        let e4 = world.create_entity().with(Parent { entity: e2 }).build();
+
+        let e5 = world.create_entity().with(Parent { entity: e3 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert!(hierarchy.all_children_iter(e0).eq([e1].iter().cloned()));
+        assert_eq!(hierarchy.all_children_iter(e1).next(), None);
+        assert!(hierarchy
+            .all_children_iter(e2)
+            .eq([e3, e4, e5].iter().cloned()));
+        assert!(hierarchy.all_children_iter(e3).eq([e5].iter().cloned()));
+        assert_eq!(hierarchy.all_children_iter(e4).next(), None);
+        assert_eq!(hierarchy.all_children_iter(e5).next(), None);
+    }
+
+    #[test]
+    fn test_all_children_iter_rev() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+
+        let e2 = world.create_entity().build();
+
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+
+        let e4 = world.create_entity().with(Parent { entity: e2 }).build();
+
+        let e5 = world.create_entity().with(Parent { entity: e3 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert!(hierarchy.all_children_iter_rev(e0).eq([e1].iter().cloned()));
+        assert_eq!(hierarchy.all_children_iter_rev(e1).next(), None);
+        assert!(hierarchy
+            .all_children_iter_rev(e2)
+            .eq([e5, e4, e3].iter().cloned()));
+        assert!(hierarchy.all_children_iter_rev(e3).eq([e5].iter().cloned()));
+        assert_eq!(hierarchy.all_children_iter_rev(e5).next(), None);
+    }
+
+    #[test]
+    fn test_all_children() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+
+        let e2 = world.create_entity().build();
+
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+
+        let e4 = world.create_entity().with(Parent { entity: e2 }).build();
+
+        let e5 = world.create_entity().with(Parent { entity: e3 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        use hibitset::BitSetLike;
+
+        assert!(hierarchy
+            .all_children(e0)
+            .iter()
+            .eq([e1].iter().map(|e| e.id())));
+        assert_eq!(hierarchy.all_children(e1).iter().next(), None);
+        assert!(hierarchy
+            .all_children(e2)
+            .iter()
+            .eq([e3, e4, e5].iter().map(|e| e.id())));
+        assert!(hierarchy
+            .all_children(e3)
+            .iter()
+            .eq([e5].iter().map(|e| e.id())));
+        assert_eq!(hierarchy.all_children(e4).iter().next(), None);
+        assert_eq!(hierarchy.all_children(e5).iter().next(), None);
+    }
+
+    #[test]
+    fn test_append_leaf() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let mut reader_id = world.write_resource::<Hierarchy<Parent>>().track();
+
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        system.run_now(&world);
+        world.maintain();
+        world
+            .read_resource::<Hierarchy<Parent>>()
+            .changed()
+            .read(&mut reader_id)
+            .count();
+
+        let leaves = (0..1000)
+            .map(|_| world.create_entity().build())
+            .collect::<Vec<_>>();
+        {
+            let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+            for leaf in &leaves {
+                hierarchy.append_leaf(*leaf, e1);
+            }
+            assert!(hierarchy.changed().read(&mut reader_id).eq(leaves
+                .iter()
+                .map(|e| HierarchyEvent::Modified(*e))
+                .collect::<Vec<_>>()
+                .iter()));
+        }
+
+        {
+            let mut parents = world.write_storage::<Parent>();
+            for leaf in &leaves {
+                parents.insert(*leaf, Parent { entity: e1 }).unwrap();
+            }
+        }
+        system.run_now(&world);
+        world.maintain();
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.children(e1), leaves.as_slice());
+        assert_eq!(hierarchy.all()[0], e1);
+        assert_eq!(&hierarchy.all()[1..], leaves.as_slice());
+        assert!(leaves
+            .iter()
+            .all(|leaf| hierarchy.parent(*leaf) == Some(e1)));
+        assert!(hierarchy
+            .all_children_iter(e0)
+            .eq(Some(e1).into_iter().chain(leaves.iter().cloned())));
+    }
+
+    #[test]
+    fn test_subtree_set() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        use hibitset::BitSetLike;
+
+        assert!(hierarchy
+            .subtree_set(e0)
+            .iter()
+            .eq([e0, e1, e2, e3].iter().map(|e| e.id())));
+        assert!(hierarchy
+            .subtree_set(e1)
+            .iter()
+            .eq([e1, e2].iter().map(|e| e.id())));
+        assert!(hierarchy.subtree_set(e4).iter().eq(Some(e4.id())));
+    }
+
+    #[test]
+    fn test_reparent_placement() {
+        fn reparent_with(placement: ReparentPlacement) -> (Vec<Entity>, Entity) {
+            let mut world = World::new();
+            world.register::<Parent>();
+            let mut system = HierarchySystem::<Parent>::new(&mut world);
+            world
+                .write_resource::<Hierarchy<Parent>>()
+                .set_reparent_placement(placement);
+
+            let a = world.create_entity().build();
+            let b = world.create_entity().build();
+            let _a0 = world.create_entity().with(Parent { entity: a }).build();
+            let a1 = world.create_entity().with(Parent { entity: a }).build();
+            let _a2 = world.create_entity().with(Parent { entity: a }).build();
+            let _b0 = world.create_entity().with(Parent { entity: b }).build();
+            let _b1 = world.create_entity().with(Parent { entity: b }).build();
+            let _b2 = world.create_entity().with(Parent { entity: b }).build();
+            system.run_now(&world);
+            world.maintain();
+
+            world.write_storage::<Parent>().get_mut(a1).unwrap().entity = b;
+            system.run_now(&world);
+            world.maintain();
+
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(hierarchy.parent(a1), Some(b));
+            assert_eq!(hierarchy.children(a).len(), 2);
+            (hierarchy.children(b).to_vec(), a1)
+        }
+
+        let (children, a1) = reparent_with(ReparentPlacement::End);
+        assert_eq!(children.iter().position(|e| *e == a1), Some(3));
+        let (children, a1) = reparent_with(ReparentPlacement::Front);
+        assert_eq!(children.iter().position(|e| *e == a1), Some(0));
+        let (children, a1) = reparent_with(ReparentPlacement::PreserveRank);
+        assert_eq!(children.iter().position(|e| *e == a1), Some(1));
+    }
+
+    #[test]
+    fn test_parented_leaves() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        let leaves = hierarchy.parented_leaves().collect::<Vec<_>>();
+        assert_eq!(leaves.len(), 2);
+        assert!(leaves.contains(&e2));
+        assert!(leaves.contains(&e3));
+        assert!(!leaves.contains(&e0));
+        assert!(!leaves.contains(&e1));
+        assert!(!leaves.contains(&e4));
+    }
+
+    #[test]
+    fn test_predecessor_successor() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.all(), &[e1, e2, e3]);
+        assert_eq!(hierarchy.predecessor(e1), None);
+        assert_eq!(hierarchy.predecessor(e2), Some(e1));
+        assert_eq!(hierarchy.predecessor(e3), Some(e2));
+        assert_eq!(hierarchy.successor(e1), Some(e2));
+        assert_eq!(hierarchy.successor(e2), Some(e3));
+        assert_eq!(hierarchy.successor(e3), None);
+        assert_eq!(hierarchy.predecessor(e0), None);
+        assert_eq!(hierarchy.successor(e0), None);
+    }
+
+    #[test]
+    fn test_references() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let mut reader_id = world.write_resource::<Hierarchy<Parent>>().track();
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+
+        system.run_now(&world);
+        delete_removals(&mut world, &mut reader_id);
+        world.maintain();
+        {
+            let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+            hierarchy.add_reference(e1, e3);
+            hierarchy.add_reference(e1, e2);
+            assert_eq!(hierarchy.references(e1), &[e3, e2]);
+            assert_eq!(hierarchy.references(e3), &[]);
+            assert_eq!(hierarchy.all(), &[e1, e3]);
+        }
+
+        let _ = world.delete_entity(e2);
+        system.run_now(&world);
+        delete_removals(&mut world, &mut reader_id);
+        world.maintain();
+
+        assert!(!world.is_alive(e3));
+        assert!(world.is_alive(e1));
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.all(), &[e1]);
+        assert_eq!(hierarchy.parent(e1), Some(e0));
+    }
+
+    #[test]
+    fn test_ordinal_in_subtree() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+        let e5 = world.create_entity().build();
+        let e6 = world.create_entity().with(Parent { entity: e5 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        for ancestor in &[e0, e1, e3] {
+            for entity in &[e1, e2, e3, e4] {
+                assert_eq!(
+                    hierarchy.ordinal_in_subtree(*ancestor, *entity),
+                    hierarchy
+                        .all_children_iter(*ancestor)
+                        .position(|e| e == *entity)
+                );
+            }
+        }
+        assert_eq!(hierarchy.ordinal_in_subtree(e0, e4), Some(3));
+        assert_eq!(hierarchy.ordinal_in_subtree(e0, e0), None);
+        assert_eq!(hierarchy.ordinal_in_subtree(e0, e6), None);
+        assert_eq!(hierarchy.ordinal_in_subtree(e1, e4), None);
+    }
+
+    #[test]
+    fn test_descendants_paged() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let root = world.create_entity().build();
+        let mut expected = Vec::new();
+        for _ in 0..10 {
+            let child = world.create_entity().with(Parent { entity: root }).build();
+            expected.push(child);
+            for _ in 0..3 {
+                let grandchild = world.create_entity().with(Parent { entity: child }).build();
+                expected.push(grandchild);
+            }
+        }
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        let mut cursor = PageCursor::new();
+        let mut yielded = Vec::new();
+        assert!(!cursor.is_done());
+        while !cursor.is_done() {
+            let page = hierarchy.descendants_paged(root, &mut cursor, 7);
+            assert!(page.len() <= 7);
+            yielded.extend(page);
+        }
+        assert!(hierarchy.descendants_paged(root, &mut cursor, 7).is_empty());
+
+        // breadth-first, all children before any grandchild
+        assert!(yielded[..10]
+            .iter()
+            .all(|e| hierarchy.parent(*e) == Some(root)));
+        yielded.sort();
+        expected.sort();
+        assert_eq!(yielded, expected);
+    }
+
+    fn assert_structure_world() -> (World, Vec<Entity>) {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        system.run_now(&world);
+        world.maintain();
+        (world, vec![e0, e1, e2])
+    }
+
+    #[test]
+    fn test_assert_structure() {
+        let (world, e) = assert_structure_world();
+        world
+            .read_resource::<Hierarchy<Parent>>()
+            .assert_structure(&[(e[0], None), (e[1], Some(e[0])), (e[2], Some(e[1]))]);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected parent")]
+    fn test_assert_structure_wrong_parent() {
+        let (world, e) = assert_structure_world();
+        world
+            .read_resource::<Hierarchy<Parent>>()
+            .assert_structure(&[(e[1], Some(e[0])), (e[2], Some(e[0]))]);
+    }
+
+    #[test]
+    #[should_panic(expected = "missing from the expected structure")]
+    fn test_assert_structure_missing() {
+        let (world, e) = assert_structure_world();
+        world
+            .read_resource::<Hierarchy<Parent>>()
+            .assert_structure(&[(e[1], Some(e[0]))]);
+    }
+
+    #[test]
+    fn test_remove_subtree_to_depth() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let mut reader_id = world.write_resource::<Hierarchy<Parent>>().track();
+        let e0 = world.create_entity().build();
+        let root = world.create_entity().with(Parent { entity: e0 }).build();
+        let c0 = world.create_entity().with(Parent { entity: root }).build();
+        let c1 = world.create_entity().with(Parent { entity: root }).build();
+        let g0 = world.create_entity().with(Parent { entity: c0 }).build();
+        let g1 = world.create_entity().with(Parent { entity: c1 }).build();
+        let g2 = world.create_entity().with(Parent { entity: g0 }).build();
+        let other = world.create_entity().with(Parent { entity: e0 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        world
+            .read_resource::<Hierarchy<Parent>>()
+            .changed()
+            .read(&mut reader_id)
+            .count();
+
+        let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+        let removed = hierarchy.remove_subtree_to_depth(root, 1);
+        assert_eq!(removed, vec![root, c0, c1]);
+        hierarchy.assert_structure(&[
+            (e0, None),
+            (root, None),
+            (c0, None),
+            (c1, None),
+            (g0, None),
+            (g1, None),
+            (g2, Some(g0)),
+            (other, Some(e0)),
+        ]);
+        assert_eq!(hierarchy.all(), &[g2, other]);
+        assert_eq!(hierarchy.children(e0), &[other]);
+        assert_eq!(hierarchy.children(root), &[]);
+        assert_eq!(hierarchy.children(g0), &[g2]);
+        assert_eq!(hierarchy.successor(g2), Some(other));
+
+        let events = hierarchy
+            .changed()
+            .read(&mut reader_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                HierarchyEvent::Modified(g2),
+                HierarchyEvent::Modified(g0),
+                HierarchyEvent::Modified(g1),
+                HierarchyEvent::Removed(root),
+                HierarchyEvent::Removed(c0),
+                HierarchyEvent::Removed(c1),
+            ]
+        );
+
+        // removing the last child of e0 makes it a leaf
+        assert_eq!(hierarchy.remove_subtree_to_depth(other, 0), vec![other]);
+        assert!(hierarchy.changed().read(&mut reader_id).eq(&[
+            HierarchyEvent::Removed(other),
+            HierarchyEvent::BecameLeaf(e0)
+        ]));
+    }
+
+    #[test]
+    fn test_edge_count() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert!(hierarchy.is_empty());
+            assert_eq!(hierarchy.edge_count(), 0);
+            assert_eq!(hierarchy.len(), 0);
+        }
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let _e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let _e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().build();
+        let _e5 = world.create_entity().with(Parent { entity: e4 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert!(!hierarchy.is_empty());
+        assert_eq!(hierarchy.len(), 6);
+        assert_eq!(hierarchy.tree_count(), 2);
+        assert_eq!(hierarchy.edge_count(), 4);
+        assert_eq!(
+            hierarchy.edge_count(),
+            hierarchy.len() - hierarchy.tree_count()
+        );
+    }
+
+    #[test]
+    fn test_first_layer() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().build();
+        let e5 = world.create_entity().with(Parent { entity: e4 }).build();
+        let _e6 = world.create_entity().with(Parent { entity: e5 }).build();
+        let _e7 = world.create_entity().with(Parent { entity: e2 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert!(hierarchy.first_layer().eq(vec![e1, e3, e5]));
+    }
+
+    #[test]
+    fn test_structural_hash() {
+        fn build(reverse: bool) -> (World, HierarchySystem<Parent>, Vec<Entity>) {
+            let mut world = World::new();
+            world.register::<Parent>();
+            let mut system = HierarchySystem::<Parent>::new(&mut world);
+            let e = (0..5)
+                .map(|_| world.create_entity().build())
+                .collect::<Vec<_>>();
+            let mut links = vec![(e[1], e[0]), (e[2], e[1]), (e[3], e[0]), (e[4], e[3])];
+            if reverse {
+                links.reverse();
+            }
+            for (child, parent) in links {
+                world
+                    .write_storage::<Parent>()
+                    .insert(child, Parent { entity: parent })
+                    .unwrap();
+                system.run_now(&world);
+                world.maintain();
+            }
+            (world, system, e)
+        }
+
+        let (world_a, _, _) = build(false);
+        let (mut world_b, mut system_b, e) = build(true);
+        let hash_a = world_a
+            .read_resource::<Hierarchy<Parent>>()
+            .structural_hash();
+        let hash_b = world_b
+            .read_resource::<Hierarchy<Parent>>()
+            .structural_hash();
+        assert_ne!(
+            world_a.read_resource::<Hierarchy<Parent>>().all(),
+            world_b.read_resource::<Hierarchy<Parent>>().all()
+        );
+        assert_eq!(hash_a, hash_b);
+
+        world_b
+            .write_storage::<Parent>()
+            .get_mut(e[4])
+            .unwrap()
+            .entity = e[1];
+        system_b.run_now(&world_b);
+        world_b.maintain();
+        assert_ne!(
+            hash_a,
+            world_b
+                .read_resource::<Hierarchy<Parent>>()
+                .structural_hash()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_snapshot_serde() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let snapshot = world.read_resource::<Hierarchy<Parent>>().snapshot();
+        let json = ::serde_json::to_string(&snapshot).unwrap();
+        let restored = ::serde_json::from_str(&json).unwrap();
+        let hierarchy = {
+            let entities = world.entities();
+            Hierarchy::<Parent>::from_snapshot(
+                world.write_storage::<Parent>().register_reader(),
+                &restored,
+                |id, gen| Some(entities.entity(id)).filter(|e| e.gen().id() == gen),
+            )
+        };
+        assert_eq!(restored, snapshot);
+        assert_eq!(hierarchy.snapshot(), snapshot);
+        assert_eq!(hierarchy.children(e0), &[e1, e2]);
+        assert_eq!(hierarchy.children(e1), &[e3]);
+        assert_eq!(hierarchy.parent(e3), Some(e1));
+        assert_eq!(hierarchy.depth(e3), Some(1));
+        assert_eq!(hierarchy.descendant_count(e0), 3);
+
+        // entities with a different generation after loading are dropped with their subtree
+        world.delete_entity(e1).unwrap();
+        world.maintain();
+        let entities = world.entities();
+        let hierarchy = Hierarchy::<Parent>::from_snapshot(
+            world.write_storage::<Parent>().register_reader(),
+            &restored,
+            |id, gen| Some(entities.entity(id)).filter(|e| e.gen().id() == gen),
+        );
+        assert_eq!(hierarchy.all(), &[e2]);
+        assert_eq!(hierarchy.parent(e3), None);
+    }
+
+    #[test]
+    fn test_to_adjacency() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        world.write_storage::<Parent>().get_mut(e4).unwrap().entity = e2;
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        let mut expected = HashMap::new();
+        expected.insert(e0.id(), vec![e1.id(), e3.id()]);
+        expected.insert(e1.id(), vec![e2.id()]);
+        expected.insert(e2.id(), vec![e4.id()]);
+        assert_eq!(hierarchy.to_adjacency(), expected);
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        let dot = hierarchy.to_dot();
+        assert!(dot.starts_with("digraph hierarchy {\n"));
+        assert!(dot.ends_with("}\n"));
+        for entity in &[e0, e1, e2, e3] {
+            let node = format!("    {} [label=\"{}\"];\n", entity.id(), entity.id());
+            assert!(dot.contains(&node), "{}", dot);
+        }
+        for (parent, child) in &[(e0, e1), (e1, e2), (e0, e3)] {
+            let edge = format!("    {} -> {};\n", parent.id(), child.id());
+            assert!(dot.contains(&edge), "{}", dot);
+        }
+        assert_eq!(dot.matches("->").count(), 3);
+
+        let dot = hierarchy.to_dot_with(|entity| format!("\"e{}\"", entity.id()));
+        let node = format!("    {} [label=\"\\\"e{}\\\"\"];\n", e2.id(), e2.id());
+        assert!(dot.contains(&node), "{}", dot);
+    }
+
+    #[test]
+    fn test_changed_since() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e0 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let snapshot = world.read_resource::<Hierarchy<Parent>>().snapshot();
+        assert!(!world
+            .read_resource::<Hierarchy<Parent>>()
+            .changed_since(&snapshot));
+
+        // rewriting the same parent is not a change
+        world.write_storage::<Parent>().get_mut(e1).unwrap().entity = e0;
+        system.run_now(&world);
+        world.maintain();
+        assert!(!world
+            .read_resource::<Hierarchy<Parent>>()
+            .changed_since(&snapshot));
+
+        world.write_storage::<Parent>().get_mut(e2).unwrap().entity = e1;
+        system.run_now(&world);
+        world.maintain();
+        assert!(world
+            .read_resource::<Hierarchy<Parent>>()
+            .changed_since(&snapshot));
+    }
+
+    #[test]
+    fn test_subtree_clean() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let root = world.create_entity().build();
+        let a = world.create_entity().with(Parent { entity: root }).build();
+        let a0 = world.create_entity().with(Parent { entity: a }).build();
+        let a1 = world.create_entity().with(Parent { entity: a }).build();
+        let b = world.create_entity().with(Parent { entity: root }).build();
+        let b0 = world.create_entity().with(Parent { entity: b }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert!(!hierarchy.subtree_clean(root));
+            assert!(!hierarchy.subtree_clean(a));
+        }
+
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert!(hierarchy.subtree_clean(root));
+            assert!(hierarchy.subtree_clean(a));
+            assert!(hierarchy.subtree_clean(b));
+        }
+
+        world.write_storage::<Parent>().get_mut(a1).unwrap().entity = a0;
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert!(!hierarchy.subtree_clean(root));
+            assert!(!hierarchy.subtree_clean(a));
+            assert!(hierarchy.subtree_clean(b));
+            assert!(hierarchy.subtree_clean(b0));
+        }
+
+        world.write_storage::<Parent>().remove(b0);
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert!(!hierarchy.subtree_clean(root));
+            assert!(hierarchy.subtree_clean(a));
+            assert!(!hierarchy.subtree_clean(b));
+        }
+
+        world.write_storage::<Parent>().get_mut(a0).unwrap().entity = b;
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert!(!hierarchy.subtree_clean(a));
+        assert!(!hierarchy.subtree_clean(b));
+    }
+
+    #[test]
+    fn test_subtree_levels() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e3 }).build();
+        let e6 = world.create_entity().with(Parent { entity: e2 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(
+            hierarchy.subtree_levels(e0),
+            vec![vec![e1, e2], vec![e3, e4, e6], vec![e5]]
+        );
+        assert_eq!(hierarchy.subtree_levels(e2), vec![vec![e4, e6]]);
+        assert!(hierarchy.subtree_levels(e5).is_empty());
+    }
+
+    #[test]
+    fn test_events_without_reader() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().build();
+        let e2 = world.create_entity().with(Parent { entity: e0 }).build();
+        for i in 0..1000 {
+            let parent = if i % 2 == 0 { e1 } else { e0 };
+            world.write_storage::<Parent>().get_mut(e2).unwrap().entity = parent;
+            system.run_now(&world);
+            world.maintain();
+        }
+        {
+            let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+            assert!(!hierarchy.changed.would_write());
+        }
+
+        let mut reader_id = world.write_resource::<Hierarchy<Parent>>().track();
+        assert_eq!(
+            world
+                .read_resource::<Hierarchy<Parent>>()
+                .changed()
+                .read(&mut reader_id)
+                .count(),
+            0
+        );
+        world.write_storage::<Parent>().get_mut(e2).unwrap().entity = e1;
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert!(hierarchy.changed().read(&mut reader_id).eq([
+            HierarchyEvent::Reparented {
+                entity: e2,
+                old_parent: e0,
+                new_parent: e1
+            },
+            HierarchyEvent::Modified(e2),
+            HierarchyEvent::BecameLeaf(e0)
+        ]
+        .iter()));
+    }
+
+    #[test]
+    fn test_common_ancestor_of() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e6 = world.create_entity().build();
+        let e7 = world.create_entity().with(Parent { entity: e6 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.common_ancestor_of(vec![e2, e4, e3]), Some(e1));
+        assert_eq!(hierarchy.common_ancestor_of(vec![e2, e4, e5]), Some(e0));
+        assert_eq!(hierarchy.common_ancestor_of(vec![e4, e3, e1]), Some(e1));
+        assert_eq!(hierarchy.common_ancestor_of(vec![e4]), Some(e4));
+        assert_eq!(hierarchy.common_ancestor_of(vec![e2, e7]), None);
+        assert_eq!(hierarchy.common_ancestor_of(vec![]), None);
+    }
+
+    #[test]
+    fn test_children_sorted() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e0 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        world.write_storage::<Parent>().get_mut(e2).unwrap().entity = e0;
+        system.run_now(&world);
+        world.maintain();
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.all(), &[e2, e3, e4]);
+        assert_eq!(hierarchy.children(e0), &[e3, e4, e2]);
+        assert_eq!(hierarchy.children_sorted(e0), vec![e2, e3, e4]);
+        assert!(hierarchy.children_sorted(e1).is_empty());
+    }
+
+    #[test]
+    fn test_cycle_handler() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let rejected = Arc::new(Mutex::new(Vec::new()));
+        {
+            let rejected = rejected.clone();
+            world
+                .write_resource::<Hierarchy<Parent>>()
+                .set_cycle_handler(move |child, parent| {
+                    rejected.lock().unwrap().push((child, parent))
+                });
+        }
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+        system.run_now(&world);
+        world.maintain();
+        assert!(rejected.lock().unwrap().is_empty());
+
+        // insert closing a cycle
+        world
+            .write_storage::<Parent>()
+            .insert(e0, Parent { entity: e2 })
+            .unwrap();
+        system.run_now(&world);
+        world.maintain();
+        assert_eq!(*rejected.lock().unwrap(), vec![(e0, e2)]);
+
+        // reparent closing a cycle
+        world.write_storage::<Parent>().get_mut(e1).unwrap().entity = e3;
+        system.run_now(&world);
+        world.maintain();
+        assert_eq!(*rejected.lock().unwrap(), vec![(e0, e2), (e1, e3)]);
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        hierarchy.assert_structure(&[(e0, None), (e1, Some(e0)), (e2, Some(e1)), (e3, Some(e1))]);
+        assert_eq!(hierarchy.all(), &[e1, e2, e3]);
+    }
+
+    #[test]
+    fn test_parent_and_siblings() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.parent_and_siblings(e2), Some((e0, vec![e1, e3])));
+        assert_eq!(hierarchy.parent_and_siblings(e4), Some((e3, vec![])));
+        assert_eq!(hierarchy.parent_and_siblings(e0), None);
+    }
+
+    #[test]
+    fn test_visible_descendants() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e4 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+        assert!(hierarchy
+            .visible_descendants(e0)
+            .eq(hierarchy.all_children_iter(e0)));
+
+        hierarchy.set_collapsed(e1, true);
+        assert!(hierarchy.is_collapsed(e1));
+        assert!(hierarchy.visible_descendants(e0).eq(vec![e1, e4, e5]));
+        assert!(hierarchy.visible_descendants(e1).next().is_none());
+        assert!(hierarchy.visible_descendants(e2).eq(vec![e3]));
+        assert_eq!(hierarchy.all_children_iter(e0).count(), 5);
+
+        hierarchy.set_collapsed(e1, false);
+        hierarchy.set_collapsed(e0, true);
+        assert!(hierarchy.visible_descendants(e0).next().is_none());
+        assert!(hierarchy.visible_descendants(e1).eq(vec![e2, e3]));
+    }
+
+    #[test]
+    fn test_depth_cache() {
+        fn walk(hierarchy: &Hierarchy<Parent>, entity: Entity) -> Option<usize> {
+            let mut parent = hierarchy.parent(entity)?;
+            let mut depth = 0;
+            while let Some(next) = hierarchy.parent(parent) {
+                parent = next;
+                depth += 1;
+            }
+            Some(depth)
+        }
+
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e4 }).build();
+        let e6 = world.create_entity().build();
+        let entities = [e0, e1, e2, e3, e4, e5, e6];
+
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(hierarchy.depth(e0), None);
+            assert_eq!(hierarchy.depth(e1), Some(0));
+            assert_eq!(hierarchy.depth(e3), Some(2));
+            assert_eq!(hierarchy.depth(e5), Some(1));
+        }
+
+        for (child, parent) in &[(e4, e3), (e2, e0), (e1, e5), (e5, e6)] {
+            world
+                .write_storage::<Parent>()
+                .get_mut(*child)
+                .unwrap()
+                .entity = *parent;
+            system.run_now(&world);
+            world.maintain();
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            for entity in &entities {
+                assert_eq!(hierarchy.depth(*entity), walk(&hierarchy, *entity));
+            }
+        }
+
+        let e7 = world.create_entity().build();
+        world
+            .write_resource::<Hierarchy<Parent>>()
+            .append_leaf(e7, e1);
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.depth(e7), Some(2));
+        assert_eq!(hierarchy.depth(e7), walk(&hierarchy, e7));
+    }
+
+    #[test]
+    fn test_depth_changed_entities() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e4 }).build();
+        let e6 = world.create_entity().with(Parent { entity: e3 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        assert!(world
+            .read_resource::<Hierarchy<Parent>>()
+            .depth_changed_entities()
+            .is_empty());
+
+        world.write_storage::<Parent>().get_mut(e4).unwrap().entity = e2;
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(hierarchy.depth_changed_entities(), &[e5]);
+            assert_eq!(hierarchy.depth(e5), Some(3));
+        }
+
+        world.write_storage::<Parent>().get_mut(e1).unwrap().entity = e6;
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            let changed = hierarchy.depth_changed_entities();
+            assert_eq!(changed.len(), 3);
+            assert!(changed.contains(&e2));
+            assert!(changed.contains(&e4));
+            assert!(changed.contains(&e5));
+        }
+
+        system.run_now(&world);
+        world.maintain();
+        assert!(world
+            .read_resource::<Hierarchy<Parent>>()
+            .depth_changed_entities()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_iter_with_children() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+        let _e4 = world.create_entity().with(Parent { entity: e3 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        let pairs = hierarchy.iter_with_children().collect::<Vec<_>>();
+        assert_eq!(pairs.len(), hierarchy.all().len());
+        for ((entity, children), sorted) in pairs.iter().zip(hierarchy.all()) {
+            assert_eq!(entity, sorted);
+            assert_eq!(*children, hierarchy.children(*entity));
+        }
+        assert_eq!(pairs[0], (e1, &[e2, e3][..]));
+        assert_eq!(pairs[1], (e2, &[][..]));
+    }
+
+    #[test]
+    fn test_lazy_removal() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        world
+            .write_resource::<Hierarchy<Parent>>()
+            .set_lazy_removal(true);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        world.write_storage::<Parent>().remove(e1);
+        system.run_now(&world);
+        world.maintain();
+
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(hierarchy.all(), &[e3, e4]);
+            assert_eq!(hierarchy.len(), 3);
+            assert_eq!(hierarchy.parent(e1), None);
+            assert_eq!(hierarchy.parent(e2), None);
+            assert_eq!(hierarchy.depth(e2), None);
+            assert_eq!(hierarchy.children(e0), &[e3]);
+            assert!(hierarchy.all_children_iter(e0).eq(vec![e3, e4]));
+            assert!(hierarchy.parented_leaves().eq(vec![e4]));
+            assert!(hierarchy.first_layer().eq(vec![e3]));
+            assert_eq!(hierarchy.predecessor(e3), None);
+            assert_eq!(hierarchy.successor(e3), Some(e4));
+            assert!(hierarchy
+                .iter_with_children()
+                .map(|(entity, _)| entity)
+                .eq(vec![e3, e4]));
+            hierarchy.assert_structure(&[(e3, Some(e0)), (e4, Some(e3))]);
+            assert_eq!(hierarchy.predecessor(e4), Some(e3));
+        }
+
+        // removing and inserting in the same frame
+        world.write_storage::<Parent>().remove(e4);
+        let e5 = world.create_entity().with(Parent { entity: e3 }).build();
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.all(), &[e3, e5]);
+        assert!(hierarchy.all_children_iter(e0).eq(vec![e3, e5]));
+    }
+
+    #[test]
+    fn test_ancestor_set() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e2 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        use hibitset::BitSetLike;
+
+        assert!(hierarchy
+            .ancestor_set(e4)
+            .iter()
+            .eq([e0, e1, e2].iter().map(|e| e.id())));
+        assert!(hierarchy.ancestor_set(e3).iter().eq(Some(e0.id())));
+        assert!(hierarchy.ancestor_set(e0).is_empty());
+    }
+
+    #[test]
+    fn test_for_each_tree() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+        let _e4 = world.create_entity().with(Parent { entity: e1 }).build();
+        let _e5 = world.create_entity().with(Parent { entity: e0 }).build();
+        let _e6 = world.create_entity().with(Parent { entity: e3 }).build();
+        let _e7 = world.create_entity().build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        let mut trees = Vec::new();
+        hierarchy.for_each_tree(|root, nodes| {
+            let nodes = nodes.collect::<Vec<_>>();
+            assert_eq!(nodes[0], root);
+            assert!(nodes[1..]
+                .iter()
+                .cloned()
+                .eq(hierarchy.all_children_iter(root)));
+            trees.push((root, nodes.len()));
+        });
+        assert_eq!(trees, vec![(e0, 4), (e2, 3)]);
+        assert_eq!(trees.len(), hierarchy.tree_count());
+    }
+
+    #[test]
+    fn test_tree_diameter() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        //        e0
+        //       /  \
+        //     e1    e2
+        //    /  \
+        //  e3    e4
+        //  |      |
+        //  e5    e6
+        //         |
+        //        e7
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e3 }).build();
+        let e6 = world.create_entity().with(Parent { entity: e4 }).build();
+        let _e7 = world.create_entity().with(Parent { entity: e6 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        // e5 -> e3 -> e1 -> e4 -> e6 -> e7
+        assert_eq!(hierarchy.tree_diameter(e0), 5);
+        assert_eq!(hierarchy.tree_diameter(e1), 5);
+        // e7 -> e6 -> e4
+        assert_eq!(hierarchy.tree_diameter(e4), 2);
+        assert_eq!(hierarchy.tree_diameter(e2), 0);
+        assert_eq!(hierarchy.tree_diameter(e5), 0);
+    }
+
+    #[test]
+    fn test_find_duplicate_children() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let _e3 = world.create_entity().with(Parent { entity: e0 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+        assert!(hierarchy.find_duplicate_children().is_empty());
+
+        // corrupt the hierarchy by hand
+        hierarchy.children.get_mut(&e1).unwrap().push(e2);
+        hierarchy.children.get_mut(&e1).unwrap().push(e2);
+        hierarchy.children.get_mut(&e0).unwrap().push(e1);
+        assert_eq!(
+            hierarchy.find_duplicate_children(),
+            vec![(e0, e1), (e1, e2)]
+        );
+    }
+
+    #[test]
+    fn test_group_by_root() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e6 = world.create_entity().build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        let groups = hierarchy.group_by_root(vec![e4, e2, e6, e0, e5, e3, e1]);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[&e0], vec![e2, e0, e5, e1]);
+        assert_eq!(groups[&e3], vec![e4, e3]);
+    }
+
+    #[test]
+    fn test_change_history() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        world
+            .write_resource::<Hierarchy<Parent>>()
+            .set_history_capacity(5);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        system.run_now(&world);
+        world.maintain();
+
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        system.run_now(&world);
+        world.maintain();
+
+        // nothing changed
+        system.run_now(&world);
+        world.maintain();
+
+        world.write_storage::<Parent>().remove(e1);
+        system.run_now(&world);
+        world.maintain();
+
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(hierarchy.maintain_count(), 4);
+            let history = hierarchy.change_history().collect::<Vec<_>>();
+            assert_eq!(
+                &history[..2],
+                &[
+                    (1, HierarchyEvent::Modified(e1)),
+                    (2, HierarchyEvent::Modified(e2)),
+                ]
+            );
+            // removals within a single maintain are not ordered
+            let mut removed = history[2..4].to_vec();
+            removed.sort_by_key(|(_, event)| match event {
+                HierarchyEvent::Removed(entity) => *entity,
+                _ => panic!("unexpected event {:?}", event),
+            });
+            assert_eq!(
+                removed,
+                vec![
+                    (4, HierarchyEvent::Removed(e1)),
+                    (4, HierarchyEvent::Removed(e2))
+                ]
+            );
+            assert_eq!(history[4], (4, HierarchyEvent::BecameLeaf(e0)));
+        }
+
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        system.run_now(&world);
+        world.maintain();
+        let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+        let history = hierarchy.change_history().collect::<Vec<_>>();
+        assert_eq!(history.len(), 5);
+        assert_eq!(history[0], (2, HierarchyEvent::Modified(e2)));
+        assert_eq!(history[4], (5, HierarchyEvent::Modified(e3)));
+
+        hierarchy.set_history_capacity(1);
+        assert!(hierarchy
+            .change_history()
+            .eq(Some((5, HierarchyEvent::Modified(e3)))));
+    }
+
+    #[test]
+    fn test_moved_in_sorted() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        assert_eq!(
+            world.read_resource::<Hierarchy<Parent>>().all(),
+            &[e1, e2, e3, e4]
+        );
+        assert!(world
+            .read_resource::<Hierarchy<Parent>>()
+            .moved_in_sorted()
+            .is_empty());
+
+        // move e1 below e4, which reorders everything
+        world
+            .write_storage::<Parent>()
+            .insert(e1, Parent { entity: e4 })
+            .unwrap();
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(hierarchy.all(), &[e3, e4, e1, e2]);
+            assert_eq!(hierarchy.moved_in_sorted(), &[e3, e4, e1, e2]);
+        }
+
+        // removing the last entity does not shift any others
+        world.write_storage::<Parent>().remove(e2);
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.all(), &[e3, e4, e1]);
+        assert!(hierarchy.moved_in_sorted().is_empty());
+    }
+
+    #[test]
+    fn test_simple_parent() {
+        let mut world = World::new();
+        world.register::<SimpleParent>();
+        let mut system = HierarchySystem::<SimpleParent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().build();
+        let e2 = world.create_entity().build();
+        {
+            let mut parents = world.write_storage::<SimpleParent>();
+            set_parent(&mut parents, e1, e0).unwrap();
+            set_parent(&mut parents, e2, e1).unwrap();
+        }
+
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<SimpleParent>>();
+            assert_eq!(hierarchy.all(), &[e1, e2]);
+            assert_eq!(hierarchy.parent(e2), Some(e1));
+        }
+
+        set_parent(&mut world.write_storage::<SimpleParent>(), e2, e0).unwrap();
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<SimpleParent>>();
+        assert_eq!(hierarchy.parent(e2), Some(e0));
+        assert_eq!(hierarchy.children(e0), &[e1, e2]);
+    }
+
+    #[test]
+    fn test_became_leaf() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let mut reader_id = world.write_resource::<Hierarchy<Parent>>().track();
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e0 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        world
+            .read_resource::<Hierarchy<Parent>>()
+            .changed()
+            .read(&mut reader_id)
+            .count();
+
+        // e1 loses both of its children, one is removed and the other is moved to e4
+        world.write_storage::<Parent>().remove(e2);
+        world
+            .write_storage::<Parent>()
+            .insert(e3, Parent { entity: e4 })
+            .unwrap();
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            let events = hierarchy.changed().read(&mut reader_id).collect::<Vec<_>>();
+            assert!(events.contains(&&HierarchyEvent::BecameLeaf(e1)));
+            assert!(!events.contains(&&HierarchyEvent::BecameLeaf(e4)));
+            assert_eq!(hierarchy.parent(e1), Some(e0));
+        }
+
+        world.write_storage::<Parent>().remove(e3);
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert!(hierarchy.changed().read(&mut reader_id).eq([
+            HierarchyEvent::Removed(e3),
+            HierarchyEvent::BecameLeaf(e4)
+        ]
+        .iter()));
+        assert_eq!(hierarchy.parent(e4), Some(e0));
+        assert!(hierarchy.children(e4).is_empty());
+    }
+
+    #[test]
+    fn test_flat_children() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let _e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let _e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let _e4 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e5 = world.create_entity().build();
+        let e6 = world.create_entity().with(Parent { entity: e5 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        let (flat, ranges) = hierarchy.flat_children();
+        assert_eq!(flat.len(), 5);
+        assert_eq!(ranges.len(), 3);
+        for (parent, (offset, count)) in &ranges {
+            assert_eq!(
+                &flat[*offset..*offset + *count],
+                hierarchy.children(*parent)
+            );
+        }
+        assert_eq!(ranges[&e0].1, 2);
+        assert_eq!(ranges[&e1].1, 2);
+        assert_eq!(&flat[ranges[&e5].0..][..1], &[e6]);
+    }
+
+    #[test]
+    fn test_find_ancestor() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        let even = |entity: Entity| entity.id().is_multiple_of(2);
+        assert_eq!(hierarchy.find_ancestor(e3, even), Some(e2));
+        assert_eq!(hierarchy.find_ancestor(e2, even), Some(e0));
+        assert_eq!(hierarchy.find_ancestor(e3, |e| e == e0), Some(e0));
+        assert_eq!(hierarchy.find_ancestor(e3, |e| e == e3), None);
+        assert_eq!(hierarchy.find_ancestor(e0, |_| true), None);
+    }
+
+    #[test]
+    fn test_import_order() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let order = {
+            let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+            hierarchy.import_order(&[(e0, vec![e3, e1, e2])]);
+            assert_eq!(hierarchy.children(e0), &[e3, e1, e2]);
+            hierarchy.export_order()
+        };
+        assert_eq!(order, vec![(e0, vec![e3, e1, e2])]);
+
+        // rebuild the hierarchy from the components
+        for entity in &[e1, e2, e3, e4] {
+            world.write_storage::<Parent>().remove(*entity);
+        }
+        system.run_now(&world);
+        world.maintain();
+        for &(entity, parent) in &[(e1, e0), (e2, e0), (e3, e0), (e4, e3)] {
+            world
+                .write_storage::<Parent>()
+                .insert(entity, Parent { entity: parent })
+                .unwrap();
+        }
+        system.run_now(&world);
+        world.maintain();
+
+        let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.children(e0), &[e1, e2, e3]);
+        hierarchy.import_order(&order);
+        assert_eq!(hierarchy.children(e0), &[e3, e1, e2]);
+        assert_eq!(hierarchy.parent(e4), Some(e3));
+    }
+
+    #[test]
+    fn test_auto_sort() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().build();
+        {
+            let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+            hierarchy.set_child_comparator(|a, b| b.id().cmp(&a.id()));
+            hierarchy.set_auto_sort(e0, true);
+        }
+        let c0 = world.create_entity().with(Parent { entity: e0 }).build();
+        let c1 = world.create_entity().with(Parent { entity: e1 }).build();
+        let c2 = world.create_entity().with(Parent { entity: e0 }).build();
+        let c3 = world.create_entity().with(Parent { entity: e1 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(hierarchy.children(e0), &[c2, c0]);
+            assert_eq!(hierarchy.children(e1), &[c1, c3]);
+        }
+
+        let c4 = world.create_entity().with(Parent { entity: e0 }).build();
+        world
+            .write_resource::<Hierarchy<Parent>>()
+            .set_auto_sort(e0, false);
+        let c5 = world.create_entity().with(Parent { entity: e0 }).build();
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.children(e0), &[c2, c0, c4, c5]);
+    }
+
+    #[test]
+    fn test_entities_missing_component() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        assert!(world
+            .read_resource::<Hierarchy<Parent>>()
+            .entities_missing_component(&world.read_storage())
+            .is_empty());
+
+        world.write_storage::<Parent>().remove(e2);
+        assert_eq!(
+            world
+                .read_resource::<Hierarchy<Parent>>()
+                .entities_missing_component(&world.read_storage()),
+            vec![e2]
+        );
+
+        system.run_now(&world);
+        world.maintain();
+        assert!(world
+            .read_resource::<Hierarchy<Parent>>()
+            .entities_missing_component(&world.read_storage())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_subtree_balance() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        // balanced: two branches of height 2
+        let b0 = world.create_entity().build();
+        let b1 = world.create_entity().with(Parent { entity: b0 }).build();
+        let b2 = world.create_entity().with(Parent { entity: b0 }).build();
+        world.create_entity().with(Parent { entity: b1 }).build();
+        world.create_entity().with(Parent { entity: b2 }).build();
+        // unbalanced: branches of height 1 and 3
+        let u0 = world.create_entity().build();
+        let u1 = world.create_entity().with(Parent { entity: u0 }).build();
+        world.create_entity().with(Parent { entity: u0 }).build();
+        let u3 = world.create_entity().with(Parent { entity: u1 }).build();
+        world.create_entity().with(Parent { entity: u3 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        assert_eq!(hierarchy.subtree_balance(b0), 1.0);
+        assert_eq!(hierarchy.subtree_balance(u0), 1.5);
+        assert!(hierarchy.subtree_balance(u0) > hierarchy.subtree_balance(b0));
+        assert_eq!(hierarchy.subtree_balance(u3), 1.0);
+    }
+
+    #[test]
+    fn test_destruction_order() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().build();
+        let e2 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e2 }).build();
+        world.create_entity().with(Parent { entity: e3 }).build();
+        system.run_now(&world);
+        world.maintain();
+
+        world
+            .write_storage::<Parent>()
+            .insert(e1, Parent { entity: e4 })
+            .unwrap();
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        let order = hierarchy.destruction_order().collect::<Vec<_>>();
+        assert_eq!(order.len(), hierarchy.all().len());
+        for (index, entity) in order.iter().enumerate() {
+            let parent = hierarchy.parent(*entity).unwrap();
+            if hierarchy.parent(parent).is_some() {
+                assert!(order[index + 1..].contains(&parent));
+            }
+        }
+    }
+
+    #[test]
+    fn test_entity_at_rank() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        for entity in &[e1, e2, e3] {
+            let rank = hierarchy.sort_index(*entity).unwrap();
+            assert_eq!(hierarchy.all()[rank], *entity);
+            assert_eq!(hierarchy.entity_at_rank(rank), Some(*entity));
+        }
+        assert_eq!(hierarchy.sort_index(e0), None);
+        assert_eq!(hierarchy.entity_at_rank(3), None);
+    }
+
+    #[test]
+    fn test_ancestors_of_set() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+        let _e5 = world.create_entity().with(Parent { entity: e4 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        use hibitset::BitSetLike;
+
+        assert!(hierarchy
+            .ancestors_of_set(vec![e2, e4])
+            .iter()
+            .eq([e0, e1, e3].iter().map(|e| e.id())));
+        assert!(hierarchy
+            .ancestors_of_set(vec![e2, e1])
+            .iter()
+            .eq([e0, e1].iter().map(|e| e.id())));
+        assert!(hierarchy.ancestors_of_set(None).is_empty());
+    }
+
+    #[test]
+    fn test_deletion_closure() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e4 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        assert_eq!(
+            hierarchy.deletion_closure(vec![e2, e1]),
+            hierarchy.subtree_set(e1)
+        );
+        assert_eq!(
+            hierarchy.deletion_closure(vec![e1, e3]),
+            hierarchy.subtree_set(e1)
+        );
+        use hibitset::BitSetLike;
+        assert!(hierarchy
+            .deletion_closure(vec![e3, e5])
+            .iter()
+            .eq([e3, e5].iter().map(|e| e.id())));
+        assert!(hierarchy.deletion_closure(None).is_empty());
+    }
+
+    #[test]
+    fn test_descendant_count() {
+        fn assert_counts(world: &World, entities: &[Entity]) {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            for entity in entities {
+                assert_eq!(
+                    hierarchy.descendant_count(*entity),
+                    hierarchy.all_children_iter(*entity).count(),
+                    "{:?}",
+                    entity
+                );
+            }
+        }
+
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+        let e5 = world.create_entity().build();
+        let e6 = world.create_entity().with(Parent { entity: e5 }).build();
+        let entities = [e0, e1, e2, e3, e4, e5, e6];
+
+        system.run_now(&world);
+        world.maintain();
+        assert_counts(&world, &entities);
+        assert_eq!(
+            world
+                .read_resource::<Hierarchy<Parent>>()
+                .descendant_count(e0),
+            4
+        );
+
+        // move a subtree between trees
+        world
+            .write_storage::<Parent>()
+            .insert(e3, Parent { entity: e6 })
+            .unwrap();
+        system.run_now(&world);
+        world.maintain();
+        assert_counts(&world, &entities);
+
+        // attach a whole tree below another one
+        world
+            .write_storage::<Parent>()
+            .insert(e5, Parent { entity: e2 })
+            .unwrap();
+        let e7 = world.create_entity().with(Parent { entity: e4 }).build();
+        system.run_now(&world);
+        world.maintain();
+        assert_counts(&world, &entities);
+        assert_counts(&world, &[e7]);
+        assert_eq!(
+            world
+                .read_resource::<Hierarchy<Parent>>()
+                .descendant_count(e0),
+            7
+        );
+
+        // remove a subtree
+        world.write_storage::<Parent>().remove(e6);
+        system.run_now(&world);
+        world.maintain();
+        assert_counts(&world, &entities);
+        assert_eq!(
+            world
+                .read_resource::<Hierarchy<Parent>>()
+                .descendant_count(e0),
+            3
+        );
+
+        world
+            .write_resource::<Hierarchy<Parent>>()
+            .append_leaf(e6, e5);
+        assert_counts(&world, &entities);
+        world
+            .write_resource::<Hierarchy<Parent>>()
+            .remove_subtree_to_depth(e1, 0);
+        assert_counts(&world, &entities);
+    }
+
+    #[test]
+    fn test_is_only_child() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        assert!(hierarchy.is_only_child(e2));
+        assert!(!hierarchy.is_only_child(e1));
+        assert!(!hierarchy.is_only_child(e3));
+        assert!(!hierarchy.is_only_child(e0));
+    }
+
+    #[test]
+    fn test_children_with_counts() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let _e3 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e0 }).build();
+        let _e6 = world.create_entity().with(Parent { entity: e5 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        let counts = hierarchy.children_with_counts(e0);
+        assert_eq!(counts, vec![(e1, 2), (e4, 0), (e5, 1)]);
+        for (child, count) in counts {
+            assert_eq!(count, hierarchy.descendant_count(child));
+        }
+        assert!(hierarchy.children_with_counts(e4).is_empty());
+    }
+
+    #[test]
+    fn test_child_count_leaf_root() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().build();
+
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(hierarchy.child_count(e0), 2);
+            assert_eq!(hierarchy.child_count(e1), 1);
+            assert_eq!(hierarchy.child_count(e2), 0);
+
+            assert!(hierarchy.is_leaf(e2));
+            assert!(hierarchy.is_leaf(e3));
+            assert!(hierarchy.is_leaf(e4));
+            assert!(!hierarchy.is_leaf(e0));
+            assert!(!hierarchy.is_leaf(e1));
+
+            assert!(hierarchy.is_root(e0));
+            assert!(!hierarchy.is_root(e1));
+            assert!(!hierarchy.is_root(e2));
+            assert!(!hierarchy.is_root(e4));
+        }
+
+        // entities that lose their last child are leaves, and no longer roots
+        world.write_storage::<Parent>().remove(e2);
+        world.write_storage::<Parent>().remove(e3);
+        world.write_storage::<Parent>().remove(e1);
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.child_count(e0), 0);
+        assert!(hierarchy.is_leaf(e0));
+        assert!(hierarchy.is_leaf(e1));
+        assert!(!hierarchy.is_root(e0));
+    }
+
+    #[test]
+    fn test_max_branching_factor() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        assert_eq!(
+            world
+                .read_resource::<Hierarchy<Parent>>()
+                .max_branching_factor(),
+            0
+        );
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        world.create_entity().with(Parent { entity: e0 }).build();
+        for _ in 0..3 {
+            world.create_entity().with(Parent { entity: e1 }).build();
+        }
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(
+            hierarchy.max_branching_factor(),
+            hierarchy.children(e1).len()
+        );
+        assert_eq!(hierarchy.max_branching_factor(), 3);
+    }
+
+    #[test]
+    fn test_neighborhood() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e1 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        assert_eq!(
+            hierarchy.neighborhood(e1),
+            Neighborhood {
+                parent: Some(e0),
+                siblings: vec![e2, e4],
+                children: vec![e3, e5],
+            }
+        );
+        assert_eq!(
+            hierarchy.neighborhood(e0),
+            Neighborhood {
+                parent: None,
+                siblings: vec![],
+                children: vec![e1, e2, e4],
+            }
+        );
+    }
+
+    #[test]
+    fn test_subtree_reader() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let a0 = world.create_entity().build();
+        let b0 = world.create_entity().build();
+        let (mut reader_a, mut reader_b): (SubtreeReader, SubtreeReader) = {
+            let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+            (hierarchy.subtree_reader(a0), hierarchy.subtree_reader(b0))
+        };
+        let a1 = world.create_entity().with(Parent { entity: a0 }).build();
+        let a2 = world.create_entity().with(Parent { entity: a1 }).build();
+        let b1 = world.create_entity().with(Parent { entity: b0 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(
+                reader_a.poll(&hierarchy),
+                vec![HierarchyEvent::Modified(a1), HierarchyEvent::Modified(a2)]
+            );
+            assert_eq!(
+                reader_b.poll(&hierarchy),
+                vec![HierarchyEvent::Modified(b1)]
+            );
+            assert_eq!(reader_b.root(), b0);
+        }
+
+        world.write_storage::<Parent>().remove(a2);
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(
+                reader_a.poll(&hierarchy),
+                vec![HierarchyEvent::Removed(a2), HierarchyEvent::BecameLeaf(a1)]
+            );
+            assert!(reader_b.poll(&hierarchy).is_empty());
+        }
+
+        // moving between subtrees is seen by both readers
+        world
+            .write_storage::<Parent>()
+            .insert(b1, Parent { entity: a1 })
+            .unwrap();
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        let reparented = HierarchyEvent::Reparented {
+            entity: b1,
+            old_parent: b0,
+            new_parent: a1,
+        };
+        assert_eq!(
+            reader_a.poll(&hierarchy),
+            vec![reparented, HierarchyEvent::Modified(b1)]
+        );
+        assert_eq!(
+            reader_b.poll(&hierarchy),
+            vec![
+                reparented,
+                HierarchyEvent::Modified(b1),
+                HierarchyEvent::BecameLeaf(b0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_weighted_distance() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().build();
+        let e5 = world.create_entity().with(Parent { entity: e4 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        // each edge costs the id of the child
+        let weight = |_parent: Entity, child: Entity| child.id() as f32;
+        // e2 -> e1 -> e0 -> e3
+        assert_eq!(hierarchy.weighted_distance(e2, e3, weight), Some(6.0));
+        assert_eq!(hierarchy.weighted_distance(e0, e2, weight), Some(3.0));
+        assert_eq!(hierarchy.weighted_distance(e2, e2, weight), Some(0.0));
+        assert_eq!(hierarchy.weighted_distance(e2, e5, weight), None);
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let mut reader_id = world.write_resource::<Hierarchy<Parent>>().track();
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e0 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        world
+            .read_resource::<Hierarchy<Parent>>()
+            .changed()
+            .read(&mut reader_id)
+            .count();
+
+        world.write_resource::<Hierarchy<Parent>>().begin_batch(e1);
+        for &(entity, parent) in &[(e2, e3), (e3, e0), (e2, e1)] {
+            world
+                .write_storage::<Parent>()
+                .insert(entity, Parent { entity: parent })
+                .unwrap();
+            system.run_now(&world);
+            world.maintain();
+        }
+        // e4 is outside of the batch
+        world.write_storage::<Parent>().remove(e4);
+        system.run_now(&world);
+        world.maintain();
+        assert!(world
+            .read_resource::<Hierarchy<Parent>>()
+            .changed()
+            .read(&mut reader_id)
+            .eq([HierarchyEvent::Removed(e4)].iter()));
+
+        let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+        hierarchy.end_batch(e1);
+        assert!(hierarchy
+            .changed()
+            .read(&mut reader_id)
+            .eq([HierarchyEvent::Modified(e1)].iter()));
+
+        // nothing was held back
+        hierarchy.begin_batch(e1);
+        hierarchy.end_batch(e1);
+        assert_eq!(hierarchy.changed().read(&mut reader_id).count(), 0);
+    }
+
+    #[test]
+    fn test_ancestors() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e4 = world.create_entity().build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        assert!(hierarchy.ancestors(e3).eq(vec![e2, e1]));
+        assert!(hierarchy.ancestors(e2).eq(Some(e1)));
+        assert_eq!(hierarchy.ancestors(e1).count(), 0);
+        assert_eq!(hierarchy.ancestors(e0).count(), 0);
+        assert_eq!(hierarchy.ancestors(e4).count(), 0);
+    }
+
+    #[test]
+    fn test_children_ordered_by() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let children = (0..6)
+            .map(|_| world.create_entity().with(Parent { entity: e0 }).build())
+            .collect::<Vec<_>>();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        let hidden = [children[1], children[4]];
+        let ordered = hierarchy.children_ordered_by(
+            e0,
+            |child| !hidden.contains(&child),
+            |child| std::cmp::Reverse(child.id()),
+        );
+        assert_eq!(
+            ordered,
+            vec![children[5], children[3], children[2], children[0]]
+        );
+        assert!(hierarchy
+            .children_ordered_by(children[0], |_| true, |child| child.id())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_depth() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        // straight chain
+        let c0 = world.create_entity().build();
+        let c1 = world.create_entity().with(Parent { entity: c0 }).build();
+        let c2 = world.create_entity().with(Parent { entity: c1 }).build();
+        let c3 = world.create_entity().with(Parent { entity: c2 }).build();
+        // forked tree
+        let f0 = world.create_entity().build();
+        let f1 = world.create_entity().with(Parent { entity: f0 }).build();
+        let f2 = world.create_entity().with(Parent { entity: f0 }).build();
+        let f3 = world.create_entity().with(Parent { entity: f1 }).build();
+        let f4 = world.create_entity().with(Parent { entity: f2 }).build();
+        let f5 = world.create_entity().with(Parent { entity: f4 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(hierarchy.depth(c0), None);
+            assert_eq!(hierarchy.depth(c1), Some(0));
+            assert_eq!(hierarchy.depth(c2), Some(1));
+            assert_eq!(hierarchy.depth(c3), Some(2));
+
+            assert_eq!(hierarchy.depth(f1), Some(0));
+            assert_eq!(hierarchy.depth(f2), Some(0));
+            assert_eq!(hierarchy.depth(f3), Some(1));
+            assert_eq!(hierarchy.depth(f4), Some(1));
+            assert_eq!(hierarchy.depth(f5), Some(2));
+        }
+
+        world.write_storage::<Parent>().remove(f2);
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.depth(f2), None);
+        assert_eq!(hierarchy.depth(f4), None);
+        assert_eq!(hierarchy.depth(f5), None);
+        assert_eq!(hierarchy.depth(f3), Some(1));
+    }
+
+    #[test]
+    fn test_subtree_checksum() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e4 = world.create_entity().build();
+        let e5 = world.create_entity().with(Parent { entity: e4 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let (before_e0, before_e1, before_e4) = {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            (
+                hierarchy.subtree_checksum(e0),
+                hierarchy.subtree_checksum(e1),
+                hierarchy.subtree_checksum(e4),
+            )
+        };
+
+        // unrelated changes keep the checksum stable
+        world.create_entity().with(Parent { entity: e5 }).build();
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(hierarchy.subtree_checksum(e0), before_e0);
+            assert_eq!(hierarchy.subtree_checksum(e1), before_e1);
+            assert_ne!(hierarchy.subtree_checksum(e4), before_e4);
+        }
+
+        // reparent within the subtree, which also changes the sorted order
+        world
+            .write_storage::<Parent>()
+            .insert(e3, Parent { entity: e2 })
+            .unwrap();
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_ne!(hierarchy.subtree_checksum(e0), before_e0);
+            assert_ne!(hierarchy.subtree_checksum(e1), before_e1);
+        }
+
+        // reparenting back restores the checksum
+        world
+            .write_storage::<Parent>()
+            .insert(e3, Parent { entity: e1 })
+            .unwrap();
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.subtree_checksum(e0), before_e0);
+        assert_eq!(hierarchy.subtree_checksum(e1), before_e1);
+    }
+
+    #[test]
+    fn test_roots() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let a0 = world.create_entity().build();
+        let a1 = world.create_entity().with(Parent { entity: a0 }).build();
+        world.create_entity().with(Parent { entity: a1 }).build();
+        let b0 = world.create_entity().build();
+        let b1 = world.create_entity().with(Parent { entity: b0 }).build();
+        world.create_entity().with(Parent { entity: a0 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(hierarchy.roots(), vec![a0, b0]);
+            assert_eq!(hierarchy.tree_count(), 2);
+        }
+
+        world.write_storage::<Parent>().remove(b1);
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.roots(), vec![a0]);
+        assert_eq!(hierarchy.tree_count(), 1);
+    }
+
+    #[test]
+    fn test_cross_boundary_children() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e4 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        let mut partition = BitSet::new();
+        for entity in &[e0, e1, e2] {
+            partition.add(entity.id());
+        }
+        // only the parent of e5 is outside of the partition
+        assert_eq!(hierarchy.cross_boundary_children(&partition), vec![e5]);
+        partition.remove(e2.id());
+        assert_eq!(hierarchy.cross_boundary_children(&partition), vec![e3, e5]);
+        assert_eq!(
+            hierarchy.cross_boundary_children(&BitSet::new()),
+            hierarchy.all()
+        );
+    }
+
+    #[test]
+    fn test_siblings() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e1 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        assert_eq!(hierarchy.siblings(e1), vec![e2, e3]);
+        assert_eq!(hierarchy.siblings(e2), vec![e1, e3]);
+        assert_eq!(hierarchy.siblings(e3), vec![e1, e2]);
+        assert!(hierarchy.siblings(e4).is_empty());
+        assert!(hierarchy.siblings(e0).is_empty());
+    }
+
+    #[test]
+    fn test_sibling_groups() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e5 = world.create_entity().build();
+        let e6 = world.create_entity().with(Parent { entity: e5 }).build();
+        let e7 = world.create_entity().with(Parent { entity: e4 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        // e4 loses its only child
+        world.write_storage::<Parent>().get_mut(e7).unwrap().entity = e3;
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        let groups = hierarchy.sibling_groups().collect::<Vec<_>>();
+        assert_eq!(
+            groups,
+            vec![
+                (e0, &[e1, e4][..]),
+                (e5, &[e6][..]),
+                (e1, &[e2, e3][..]),
+                (e3, &[e7][..]),
+            ]
+        );
+        assert!(hierarchy.sibling_groups().eq(groups.into_iter()));
+    }
+
+    #[test]
+    fn test_children_rev() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        assert!(hierarchy.children_rev(e0).eq(vec![e3, e2, e1]));
+        assert!(hierarchy
+            .children_rev(e0)
+            .eq(hierarchy.children(e0).iter().rev().cloned()));
+        assert_eq!(hierarchy.children_rev(e1).count(), 0);
+    }
+
+    #[test]
+    fn test_sibling_where() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let children = (0..5)
+            .map(|_| world.create_entity().with(Parent { entity: e0 }).build())
+            .collect::<Vec<_>>();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        let hidden = [children[1], children[3]];
+        let visible = |entity: Entity| !hidden.contains(&entity);
+        assert_eq!(
+            hierarchy.next_sibling_where(children[0], visible),
+            Some(children[2])
+        );
+        assert_eq!(
+            hierarchy.next_sibling_where(children[2], visible),
+            Some(children[4])
+        );
+        assert_eq!(hierarchy.next_sibling_where(children[4], visible), None);
+        assert_eq!(
+            hierarchy.prev_sibling_where(children[4], visible),
+            Some(children[2])
+        );
+        assert_eq!(hierarchy.prev_sibling_where(children[0], visible), None);
+        assert_eq!(hierarchy.next_sibling_where(e0, |_| true), None);
+    }
+
+    #[test]
+    fn test_next_prev_in_order() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e5 = world.create_entity().build();
+        let e6 = world.create_entity().with(Parent { entity: e5 }).build();
+        let e7 = world.create_entity().with(Parent { entity: e6 }).build();
+        let e8 = world.create_entity().with(Parent { entity: e5 }).build();
+        let e9 = world.create_entity().with(Parent { entity: e1 }).build();
+        let other = world.create_entity().build();
+        system.run_now(&world);
+        world.maintain();
+        // e9 stays sorted last, but is visited right after e4
+        world.write_storage::<Parent>().get_mut(e9).unwrap().entity = e4;
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        fn preorder(hierarchy: &Hierarchy<Parent>, entity: Entity, order: &mut Vec<Entity>) {
+            order.push(entity);
+            for child in hierarchy.children(entity) {
+                preorder(hierarchy, *child, order);
+            }
+        }
+        let mut expected = Vec::new();
+        for root in hierarchy.roots() {
+            preorder(&hierarchy, root, &mut expected);
+        }
+        assert_eq!(expected, vec![e0, e1, e2, e3, e4, e9, e5, e6, e7, e8]);
+
+        let mut forward = vec![e0];
+        while let Some(next) = hierarchy.next_in_order(*forward.last().unwrap()) {
+            forward.push(next);
+        }
+        assert_eq!(forward, expected);
+
+        let mut backward = vec![e8];
+        while let Some(prev) = hierarchy.prev_in_order(*backward.last().unwrap()) {
+            backward.push(prev);
+        }
+        backward.reverse();
+        assert_eq!(backward, expected);
+
+        assert_eq!(hierarchy.next_in_order(other), None);
+        assert_eq!(hierarchy.prev_in_order(other), None);
+    }
+
+    #[test]
+    fn test_preorder_index() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().build();
+        let e5 = world.create_entity().with(Parent { entity: e4 }).build();
+        let e6 = world.create_entity().with(Parent { entity: e1 }).build();
+        let other = world.create_entity().build();
+        system.run_now(&world);
+        world.maintain();
+        world.write_storage::<Parent>().get_mut(e6).unwrap().entity = e5;
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        let mut walk = vec![e0];
+        while let Some(next) = hierarchy.next_in_order(*walk.last().unwrap()) {
+            walk.push(next);
+        }
+        assert_eq!(walk, vec![e0, e1, e2, e3, e4, e5, e6]);
+        for (i, entity) in walk.iter().enumerate() {
+            assert_eq!(hierarchy.preorder_index(*entity), Some(i));
+        }
+        assert_eq!(hierarchy.sort_index(e6), Some(4));
+        assert_eq!(hierarchy.preorder_index(other), None);
+    }
+
+    #[test]
+    fn test_sibling_rank_by() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let children = (0..4)
+            .map(|_| world.create_entity().with(Parent { entity: e0 }).build())
+            .collect::<Vec<_>>();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        let sizes = [30, 10, 40, 20];
+        let size = |entity: Entity| {
+            let position = children.iter().position(|e| *e == entity).unwrap();
+            sizes[position]
+        };
+
+        assert_eq!(hierarchy.sibling_rank_by(children[0], size), Some(2));
+        assert_eq!(hierarchy.sibling_rank_by(children[1], size), Some(0));
+        assert_eq!(hierarchy.sibling_rank_by(children[2], size), Some(3));
+        assert_eq!(hierarchy.sibling_rank_by(children[3], size), Some(1));
+        assert_eq!(
+            hierarchy.sibling_rank_by(children[2], |e| std::cmp::Reverse(size(e))),
+            Some(0)
+        );
+        assert_eq!(hierarchy.sibling_rank_by(e0, size), None);
+    }
+
+    #[test]
+    fn test_is_ancestor() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e5 = world.create_entity().build();
+
+        system.run_now(&world);
+        world.maintain();
+        let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+
+        assert!(hierarchy.is_ancestor(e0, e3));
+        assert!(hierarchy.is_ancestor(e1, e3));
+        assert!(hierarchy.is_ancestor(e2, e3));
+        assert!(hierarchy.is_ancestor(e0, e4));
+        assert!(!hierarchy.is_ancestor(e3, e0));
+        assert!(!hierarchy.is_ancestor(e4, e3));
+        assert!(!hierarchy.is_ancestor(e1, e4));
+        assert!(!hierarchy.is_ancestor(e3, e3));
+        assert!(!hierarchy.is_ancestor(e0, e0));
+        assert!(!hierarchy.is_ancestor(e5, e3));
+        assert!(!hierarchy.is_ancestor(e0, e5));
+
+        assert!(hierarchy.is_descendant(e3, e0));
+        assert!(hierarchy.is_descendant(e2, e1));
+        assert!(!hierarchy.is_descendant(e0, e3));
+        assert!(!hierarchy.is_descendant(e2, e2));
+
+        // corrupt the hierarchy with a cycle, the walk must still end
+        hierarchy.current_parent.insert(e1, e3);
+        assert!(!hierarchy.is_ancestor(e4, e3));
+    }
+
+    #[test]
+    fn test_rebuild_sorted() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().build();
+        let e5 = world.create_entity().with(Parent { entity: e4 }).build();
+        let e6 = world.create_entity().with(Parent { entity: e2 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+        assert!(hierarchy.is_topologically_sorted());
+
+        // scramble the sorted list
+        hierarchy.sorted.reverse();
+        for (index, entity) in hierarchy.sorted.clone().iter().enumerate() {
+            hierarchy.entities.insert(entity.id(), index);
+        }
+        assert!(!hierarchy.is_topologically_sorted());
+
+        hierarchy.rebuild_sorted();
+        assert!(hierarchy.is_topologically_sorted());
+        // the roots keep the order they had in the scrambled list
+        assert_eq!(hierarchy.all(), &[e5, e1, e2, e6, e3]);
+        hierarchy.assert_structure(&[
+            (e1, Some(e0)),
+            (e2, Some(e1)),
+            (e3, Some(e0)),
+            (e5, Some(e4)),
+            (e6, Some(e2)),
+        ]);
+        assert!(hierarchy.all_children_iter(e0).eq(vec![e1, e2, e6, e3]));
+    }
+
+    #[test]
+    fn test_common_ancestor() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e6 = world.create_entity().build();
+        let e7 = world.create_entity().with(Parent { entity: e6 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        // same tree
+        assert_eq!(hierarchy.common_ancestor(e2, e4), Some(e1));
+        assert_eq!(hierarchy.common_ancestor(e4, e2), Some(e1));
+        assert_eq!(hierarchy.common_ancestor(e4, e5), Some(e0));
+        // different trees
+        assert_eq!(hierarchy.common_ancestor(e4, e7), None);
+        assert_eq!(hierarchy.common_ancestor(e0, e6), None);
+        // parent and child
+        assert_eq!(hierarchy.common_ancestor(e3, e4), Some(e3));
+        assert_eq!(hierarchy.common_ancestor(e4, e1), Some(e1));
+        assert_eq!(hierarchy.common_ancestor(e0, e2), Some(e0));
+        assert_eq!(hierarchy.common_ancestor(e2, e2), Some(e2));
+    }
+
+    #[test]
+    fn test_frame_delta() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        {
+            let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+            let delta = hierarchy.take_frame_delta();
+            assert_eq!(delta.inserted, vec![e1, e2, e3, e4]);
+            assert!(delta.reparented.is_empty());
+            assert!(delta.removed.is_empty());
+            assert_eq!(hierarchy.take_frame_delta(), FrameDelta::default());
+        }
+
+        let e5 = world.create_entity().with(Parent { entity: e1 }).build();
+        world
+            .write_storage::<Parent>()
+            .insert(e4, Parent { entity: e1 })
+            .unwrap();
+        world.write_storage::<Parent>().remove(e2);
+        system.run_now(&world);
+        world.maintain();
+        let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+        assert_eq!(
+            hierarchy.take_frame_delta(),
+            FrameDelta {
+                inserted: vec![e5],
+                reparented: vec![(e4, e3, e1)],
+                removed: vec![e2],
+            }
+        );
+    }
+
+    #[test]
+    fn test_try_maintain_cycle() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let _system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let a = world.create_entity().build();
+        let b = world.create_entity().with(Parent { entity: a }).build();
+        world
+            .write_storage::<Parent>()
+            .insert(a, Parent { entity: b })
+            .unwrap();
+        world.create_entity().with(Parent { entity: e0 }).build();
+
+        let result = world
+            .write_resource::<Hierarchy<Parent>>()
+            .try_maintain(world.system_data());
+        assert_eq!(result, Err(HierarchyError::Cycle(vec![b, a])));
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            // only the link closing the cycle is ignored
+            assert_eq!(hierarchy.parent(a), Some(b));
+            assert_eq!(hierarchy.parent(b), None);
+            assert_eq!(hierarchy.children(e0).len(), 1);
+        }
+
+        let result = world
+            .write_resource::<Hierarchy<Parent>>()
+            .try_maintain(world.system_data());
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "cycle")]
+    fn test_maintain_cycle_panics() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let a = world.create_entity().build();
+        let b = world.create_entity().with(Parent { entity: a }).build();
+        world
+            .write_storage::<Parent>()
+            .insert(a, Parent { entity: b })
+            .unwrap();
+        system.run_now(&world);
+    }
+
+    #[test]
+    fn test_depth_after_reparent() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e5 = world.create_entity().build();
+        system.run_now(&world);
+        world.maintain();
+
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(hierarchy.depth_after_reparent(e1, e1), None);
+            assert_eq!(hierarchy.depth_after_reparent(e1, e3), None);
+            assert_eq!(hierarchy.depth_after_reparent(e3, e0), Some(0));
+        }
+
+        for (entity, new_parent) in [(e4, e3), (e2, e5), (e4, e1)].iter().cloned() {
+            let predicted = world
+                .read_resource::<Hierarchy<Parent>>()
+                .depth_after_reparent(entity, new_parent);
+            world
+                .write_storage::<Parent>()
+                .get_mut(entity)
+                .unwrap()
+                .entity = new_parent;
+            system.run_now(&world);
+            world.maintain();
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(hierarchy.parent(entity), Some(new_parent));
+            assert_eq!(predicted, hierarchy.depth(entity));
+        }
+    }
+
+    #[test]
+    fn test_entities_at_depth() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+        let e5 = world.create_entity().build();
+        let e6 = world.create_entity().with(Parent { entity: e5 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        assert_eq!(hierarchy.entities_at_depth(0), vec![e1, e3, e6]);
+        assert_eq!(hierarchy.entities_at_depth(1), vec![e2, e4]);
+        assert!(hierarchy.entities_at_depth(2).is_empty());
+        assert!(hierarchy.entities_at_depth(100).is_empty());
+    }
+
+    #[test]
+    fn test_in_depth_range() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let mut chain = vec![];
+        let mut parent = e0;
+        for _ in 0..6 {
+            parent = world
+                .create_entity()
+                .with(Parent { entity: parent })
+                .build();
+            chain.push(parent);
+        }
+        let f1 = world
+            .create_entity()
+            .with(Parent { entity: chain[1] })
+            .build();
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+
+        assert_eq!(hierarchy.in_depth_range(0, 0), vec![chain[0]]);
+        assert_eq!(
+            hierarchy.in_depth_range(2, 4),
+            vec![chain[2], chain[3], chain[4], f1]
+        );
+        assert_eq!(hierarchy.in_depth_range(5, 10), vec![chain[5]]);
+        assert!(hierarchy.in_depth_range(6, 10).is_empty());
+        assert!(hierarchy.in_depth_range(3, 2).is_empty());
+    }
+
+    #[test]
+    fn test_self_parent() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let mut reader_id = world.write_resource::<Hierarchy<Parent>>().track();
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().build();
+        world
+            .write_storage::<Parent>()
+            .insert(e2, Parent { entity: e2 })
+            .unwrap();
+
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert!(hierarchy.changed().read(&mut reader_id).eq([
+                HierarchyEvent::Invalid(e2),
+                HierarchyEvent::Modified(e1)
+            ]
+            .iter()));
+            assert_eq!(hierarchy.all(), &[e1]);
+            assert_eq!(hierarchy.parent(e2), None);
+            assert!(hierarchy.children(e2).is_empty());
+        }
+
+        // an entity already in the hierarchy keeps its parent
+        world.write_storage::<Parent>().get_mut(e1).unwrap().entity = e1;
+        let result = world
+            .write_resource::<Hierarchy<Parent>>()
+            .try_maintain(world.system_data());
+        assert_eq!(result, Ok(()));
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert!(hierarchy
+            .changed()
+            .read(&mut reader_id)
+            .eq([HierarchyEvent::Invalid(e1)].iter()));
+        assert_eq!(hierarchy.all(), &[e1]);
+        assert_eq!(hierarchy.parent(e1), Some(e0));
+    }
+
+    #[test]
+    fn test_reparented_event() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let mut reader_id = world.write_resource::<Hierarchy<Parent>>().track();
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().build();
+        let e2 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+
+        system.run_now(&world);
+        world.maintain();
+        // inserting is not reparenting
+        assert!(world
+            .read_resource::<Hierarchy<Parent>>()
+            .changed()
+            .read(&mut reader_id)
+            .all(|event| !matches!(*event, HierarchyEvent::Reparented { .. })));
+
+        world.write_storage::<Parent>().get_mut(e2).unwrap().entity = e1;
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert!(hierarchy.changed().read(&mut reader_id).eq([
+            HierarchyEvent::Reparented {
+                entity: e2,
+                old_parent: e0,
+                new_parent: e1
+            },
+            HierarchyEvent::Modified(e2),
+            HierarchyEvent::Modified(e3),
+            HierarchyEvent::BecameLeaf(e0)
+        ]
+        .iter()));
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_invariants_after_maintain() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+        let e5 = world.create_entity().build();
+        system.run_now(&world);
+        world.maintain();
+
+        // every maintain below checks the invariants internally
+        world
+            .write_storage::<Parent>()
+            .insert(e5, Parent { entity: e4 })
+            .unwrap();
+        system.run_now(&world);
+        world.maintain();
+
+        world.write_storage::<Parent>().get_mut(e3).unwrap().entity = e2;
+        system.run_now(&world);
+        world.maintain();
+
+        world
+            .write_resource::<Hierarchy<Parent>>()
+            .set_lazy_removal(true);
+        world.write_storage::<Parent>().remove(e2);
+        system.run_now(&world);
+        world.maintain();
+
+        let _e6 = world.create_entity().with(Parent { entity: e1 }).build();
+        world.delete_entity(e0).unwrap();
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.parent(e1), None);
+        assert!(hierarchy.is_topologically_sorted());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "sorted after its child")]
+    fn test_invariants_detect_drift() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        system.run_now(&world);
+        world.maintain();
+
+        let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+        hierarchy.sorted.swap(0, 1);
+        hierarchy.entities.insert(e1.id(), 1);
+        hierarchy.entities.insert(e2.id(), 0);
+        hierarchy.check_invariants();
+    }
+
+    #[test]
+    fn test_reused_entity_index() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        system.run_now(&world);
+        world.maintain();
+
+        world.delete_entity(e2).unwrap();
+        world.maintain();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        assert_eq!(e3.id(), e2.id());
+        system.run_now(&world);
+        world.maintain();
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.parent(e2), None);
+        assert_eq!(hierarchy.parent(e3), Some(e0));
+        assert_eq!(hierarchy.sort_index(e2), None);
+        assert_eq!(hierarchy.sort_index(e3), Some(1));
+        assert_eq!(hierarchy.all(), &[e1, e3]);
+        assert!(hierarchy.is_topologically_sorted());
+    }
+
+    #[test]
+    fn test_maintain_accepting() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let _system = HierarchySystem::<Parent>::new(&mut world);
+        let mut reader_id = world.write_resource::<Hierarchy<Parent>>().track();
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e0 }).build();
+
+        let maintain = |world: &mut World, soft: &[Entity]| {
+            world
+                .write_resource::<Hierarchy<Parent>>()
+                .maintain_accepting(world.system_data(), |child, _| !soft.contains(&child));
+            world.maintain();
+        };
+
+        // the link of e4 is soft from the start
+        maintain(&mut world, &[e4]);
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(hierarchy.all(), &[e1, e2, e3]);
+            assert_eq!(hierarchy.parent(e4), None);
+            hierarchy.changed().read(&mut reader_id).count();
+        }
+
+        // accepting e4 and rejecting e2, without touching the components
+        maintain(&mut world, &[e2]);
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            assert_eq!(hierarchy.parent(e4), Some(e0));
+            assert_eq!(hierarchy.parent(e2), None);
+            // the subtree of e2 stays, and is now a tree of its own
+            assert_eq!(hierarchy.parent(e3), Some(e2));
+            assert_eq!(hierarchy.depth(e3), Some(0));
+            assert!(hierarchy.is_root(e2));
+            assert_eq!(hierarchy.descendant_count(e0), 2);
+            let events = hierarchy.changed().read(&mut reader_id).collect::<Vec<_>>();
+            assert!(events.contains(&&HierarchyEvent::Removed(e2)));
+            assert!(events.contains(&&HierarchyEvent::Modified(e3)));
+            assert!(events.contains(&&HierarchyEvent::Modified(e4)));
+            assert!(events.contains(&&HierarchyEvent::BecameLeaf(e1)));
+        }
+
+        // nothing changes while the result of accept stays the same
+        maintain(&mut world, &[e2]);
+        assert_eq!(
+            world
+                .read_resource::<Hierarchy<Parent>>()
+                .changed()
+                .read(&mut reader_id)
+                .count(),
+            0
+        );
+
+        // accepting e2 again brings its subtree back below e1
+        maintain(&mut world, &[]);
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.parent(e2), Some(e1));
+        assert_eq!(hierarchy.parent(e3), Some(e2));
+        assert_eq!(hierarchy.depth(e3), Some(2));
+        assert_eq!(hierarchy.descendant_count(e0), 4);
+        assert!(!hierarchy.is_root(e2));
+        assert!(hierarchy.is_topologically_sorted());
+    }
+
+    #[test]
+    fn test_detach() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let mut reader_id = world.write_resource::<Hierarchy<Parent>>().track();
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e0 }).build();
+        system.run_now(&world);
+        world.maintain();
+        world
+            .read_resource::<Hierarchy<Parent>>()
+            .changed()
+            .read(&mut reader_id)
+            .count();
+
+        let removed = world
+            .write_resource::<Hierarchy<Parent>>()
+            .detach(e1, &mut world.write_storage::<Parent>());
+        assert_eq!(removed.map(|parent| parent.entity), Some(e0));
+        assert!(world.read_storage::<Parent>().get(e1).is_none());
+        // nothing happens until the next maintain
+        assert_eq!(
+            world.read_resource::<Hierarchy<Parent>>().parent(e1),
+            Some(e0)
+        );
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.parent(e1), None);
+        assert_eq!(hierarchy.parent(e2), Some(e1));
+        assert_eq!(hierarchy.parent(e3), Some(e1));
+        assert_eq!(hierarchy.parent(e4), Some(e3));
+        assert_eq!(hierarchy.roots(), vec![e1, e0]);
+        assert!(hierarchy.is_root(e1));
+        assert_eq!(hierarchy.children(e0), &[e5]);
+        assert_eq!(hierarchy.depth(e2), Some(0));
+        assert_eq!(hierarchy.depth(e4), Some(1));
+        let mut events = hierarchy.changed().read(&mut reader_id).collect::<Vec<_>>();
+        events.sort_by_key(|event| format!("{:?}", event));
+        assert_eq!(
+            events,
+            vec![
+                &HierarchyEvent::Modified(e2),
+                &HierarchyEvent::Modified(e3),
+                &HierarchyEvent::Modified(e4),
+                &HierarchyEvent::Removed(e1),
+            ]
+        );
+    }
+
+    struct Parents {
+        entities: Vec<Entity>,
+    }
+
+    impl Component for Parents {
+        type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+    }
+
+    impl MultiParent for Parents {
+        fn parent_entities(&self) -> &[Entity] {
+            &self.entities
+        }
+    }
+
+    fn parents(entities: &[Entity]) -> Parents {
+        Parents {
+            entities: entities.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_multi_hierarchy_diamond() {
+        let mut world = World::new();
+        let mut system = MultiHierarchySystem::<Parents>::new(&mut world);
+        let mut reader_id = world.write_resource::<MultiHierarchy<Parents>>().track();
+
+        // e0 -> e1, e0 -> e2, (e1, e2) -> e3, inserted children first
+        let e0 = world.create_entity().build();
+        let e3 = world.create_entity().build();
+        let e2 = world.create_entity().build();
+        let e1 = world.create_entity().with(parents(&[e0])).build();
+        world
+            .write_storage::<Parents>()
+            .insert(e3, parents(&[e1, e2]))
+            .unwrap();
+        world
+            .write_storage::<Parents>()
+            .insert(e2, parents(&[e0]))
+            .unwrap();
+
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<MultiHierarchy<Parents>>();
+            let all = hierarchy.all();
+            assert_eq!(all.len(), 3);
+            let index = |e| hierarchy.sort_index(e).unwrap();
+            assert!(index(e1) < index(e3));
+            assert!(index(e2) < index(e3));
+            assert_eq!(hierarchy.parents(e3), &[e1, e2]);
+            assert_eq!(hierarchy.children(e0), &[e2, e1]);
+            assert_eq!(hierarchy.children(e1), &[e3]);
+            assert_eq!(hierarchy.children(e2), &[e3]);
+            assert!(hierarchy.is_ancestor(e0, e3));
+            assert!(!hierarchy.is_ancestor(e3, e0));
+            assert_eq!(
+                hierarchy.changed().read(&mut reader_id).count(),
+                3,
+                "one Modified event per entity"
+            );
+        }
+
+        // removing one parent keeps e3, since it is still a child of e2
+        world.delete_entity(e1).unwrap();
+        system.run_now(&world);
+        world.maintain();
+        {
+            let hierarchy = world.read_resource::<MultiHierarchy<Parents>>();
+            assert_eq!(hierarchy.all(), &[e2, e3]);
+            assert_eq!(hierarchy.parents(e3), &[e2]);
+            assert_eq!(hierarchy.children(e0), &[e2]);
+            assert_eq!(
+                hierarchy.changed().read(&mut reader_id).collect::<Vec<_>>(),
+                vec![&HierarchyEvent::Removed(e1), &HierarchyEvent::Modified(e3)]
+            );
+        }
+
+        // removing the common root removes everything below it
+        world.delete_entity(e0).unwrap();
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<MultiHierarchy<Parents>>();
+        assert!(hierarchy.all().is_empty());
+        assert_eq!(
+            hierarchy.changed().read(&mut reader_id).collect::<Vec<_>>(),
+            vec![&HierarchyEvent::Removed(e2), &HierarchyEvent::Removed(e3)]
+        );
+    }
+
+    #[test]
+    fn test_multi_hierarchy_cycle() {
+        let mut world = World::new();
+        let mut system = MultiHierarchySystem::<Parents>::new(&mut world);
+
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(parents(&[e0])).build();
+        let e2 = world.create_entity().with(parents(&[e1])).build();
+        let e3 = world.create_entity().build();
+        system.run_now(&world);
+        world.maintain();
+        let mut reader_id = world.write_resource::<MultiHierarchy<Parents>>().track();
+
+        // e1 -> e2 -> e1 would be a cycle, the other links are still applied
+        world
+            .write_storage::<Parents>()
+            .insert(e1, parents(&[e0, e2]))
+            .unwrap();
+        world
+            .write_storage::<Parents>()
+            .insert(e3, parents(&[e3, e2]))
+            .unwrap();
+        let result = world
+            .write_resource::<MultiHierarchy<Parents>>()
+            .try_maintain(world.system_data());
+        assert_eq!(result, Err(HierarchyError::Cycle(vec![e1, e2])));
+
+        let hierarchy = world.read_resource::<MultiHierarchy<Parents>>();
+        assert_eq!(hierarchy.all(), &[e1, e2, e3]);
+        assert_eq!(hierarchy.parents(e1), &[e0]);
+        assert_eq!(hierarchy.parents(e3), &[e2]);
+        assert_eq!(
+            hierarchy.changed().read(&mut reader_id).collect::<Vec<_>>(),
+            vec![&HierarchyEvent::Invalid(e3), &HierarchyEvent::Modified(e3)]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "cycle")]
+    fn test_multi_hierarchy_cycle_panics() {
+        let mut world = World::new();
+        let mut system = MultiHierarchySystem::<Parents>::new(&mut world);
+
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(parents(&[e0])).build();
+        world
+            .write_storage::<Parents>()
+            .insert(e0, parents(&[e1]))
+            .unwrap();
+        system.run_now(&world);
+    }
+
+    #[test]
+    fn test_bfs_iter() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+
+        // e0 -> e1 -> e2 -> e3 -> e4, e0 -> e5 -> e6, e0 -> e7
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e6 = world.create_entity().with(Parent { entity: e5 }).build();
+        let e7 = world.create_entity().with(Parent { entity: e0 }).build();
+        system.run_now(&world);
+        world.maintain();
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        let bfs = hierarchy.bfs_iter(e0).collect::<Vec<_>>();
+        assert_eq!(bfs, vec![e1, e5, e7, e2, e6, e3, e4]);
+        let depths = bfs
+            .iter()
+            .map(|e| hierarchy.depth(*e).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(depths, vec![0, 0, 0, 1, 1, 2, 3]);
+        assert_eq!(hierarchy.bfs_iter(e5).collect::<Vec<_>>(), vec![e6]);
+        assert_eq!(hierarchy.bfs_iter(e4).count(), 0);
+    }
+
+    #[test]
+    fn test_unrelated() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+
+        // e0 -> e1 -> e2 -> e3, e1 -> e4 -> e5, e0 -> e6, e7 -> e8
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e4 }).build();
+        let e6 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e7 = world.create_entity().build();
+        let e8 = world.create_entity().with(Parent { entity: e7 }).build();
+        system.run_now(&world);
+        world.maintain();
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        let unrelated = hierarchy.unrelated(e2);
+        for e in &[e4, e5, e6, e7, e8] {
+            assert!(unrelated.contains(e.id()));
+        }
+        for e in &[e0, e1, e2, e3] {
+            assert!(!unrelated.contains(e.id()));
+        }
+        let unrelated = hierarchy.unrelated(e0);
+        assert!(unrelated.contains(e7.id()) && unrelated.contains(e8.id()));
+        assert!(!unrelated.contains(e6.id()));
+    }
+
+    #[test]
+    fn test_dfs_iter() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+
+        // e0 -> e1 -> (e3, e4), e0 -> e2 -> (e5, e6), e4 -> e7
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e6 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e7 = world.create_entity().with(Parent { entity: e4 }).build();
+        system.run_now(&world);
+        world.maintain();
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(
+            hierarchy.dfs_preorder_iter(e0).collect::<Vec<_>>(),
+            vec![e1, e3, e4, e7, e2, e5, e6]
+        );
+        assert_eq!(
+            hierarchy.dfs_postorder_iter(e0).collect::<Vec<_>>(),
+            vec![e3, e7, e4, e1, e5, e6, e2]
+        );
+        assert_eq!(
+            hierarchy.dfs_postorder_iter(e1).collect::<Vec<_>>(),
+            vec![e3, e7, e4]
+        );
+        assert_eq!(hierarchy.dfs_preorder_iter(e7).count(), 0);
+        assert_eq!(hierarchy.dfs_postorder_iter(e7).count(), 0);
+    }
+
+    #[test]
+    fn test_coalesce_events() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        world
+            .write_resource::<Hierarchy<Parent>>()
+            .set_coalesce_events(true);
+
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        system.run_now(&world);
+        world.maintain();
+        let mut reader_id = world.write_resource::<Hierarchy<Parent>>().track();
+
+        // insert a child, move its parent and touch the grandparent in the same frame
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+        world.write_storage::<Parent>().get_mut(e2).unwrap().entity = e0;
+        world.write_storage::<Parent>().get_mut(e1).unwrap().entity = e0;
+        system.run_now(&world);
+        world.maintain();
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        let mut counts = HashMap::new();
+        for event in hierarchy.changed().read(&mut reader_id) {
+            if let HierarchyEvent::Modified(entity) = *event {
+                *counts.entry(entity).or_insert(0) += 1;
+            }
+        }
+        assert_eq!(counts.len(), 2);
+        for entity in &[e2, e3] {
+            assert_eq!(counts[entity], 1);
+        }
+    }
+
+    #[test]
+    fn test_subtree_height() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        system.run_now(&world);
+        assert_eq!(world.read_resource::<Hierarchy<Parent>>().max_depth(), 0);
+
+        // balanced: b0 -> (b1, b2), b1 -> (b3, b4), b2 -> (b5, b6)
+        let b0 = world.create_entity().build();
+        let b1 = world.create_entity().with(Parent { entity: b0 }).build();
+        let b2 = world.create_entity().with(Parent { entity: b0 }).build();
+        let mut leaves = Vec::new();
+        for parent in &[b1, b1, b2, b2] {
+            leaves.push(
+                world
+                    .create_entity()
+                    .with(Parent { entity: *parent })
+                    .build(),
+            );
+        }
+        // lopsided: c0 -> c1 -> c2 -> c3 -> c4, c0 -> c5
+        let c0 = world.create_entity().build();
+        let mut chain = vec![c0];
+        for _ in 0..4 {
+            let parent = *chain.last().unwrap();
+            chain.push(
+                world
+                    .create_entity()
+                    .with(Parent { entity: parent })
+                    .build(),
+            );
+        }
+        let c5 = world.create_entity().with(Parent { entity: c0 }).build();
+        let untracked = world.create_entity().build();
+        system.run_now(&world);
+        world.maintain();
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.subtree_height(b0), 2);
+        assert_eq!(hierarchy.subtree_height(b1), 1);
+        assert_eq!(hierarchy.subtree_height(b2), 1);
+        assert_eq!(hierarchy.subtree_height(leaves[0]), 0);
+        assert_eq!(hierarchy.subtree_height(c0), 4);
+        assert_eq!(hierarchy.subtree_height(chain[2]), 2);
+        assert_eq!(hierarchy.subtree_height(c5), 0);
+        assert_eq!(hierarchy.subtree_height(untracked), 0);
+        assert_eq!(hierarchy.max_depth(), 4);
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        system.run_now(&world);
+        let empty = world.read_resource::<Hierarchy<Parent>>().memory_usage();
+
+        let root = world.create_entity().build();
+        let mut entities = vec![root];
+        for i in 0..1000 {
+            let parent = entities[i / 4];
+            entities.push(
+                world
+                    .create_entity()
+                    .with(Parent { entity: parent })
+                    .build(),
+            );
+        }
+        system.run_now(&world);
+        world.maintain();
+        let mut reader_id = world.write_resource::<Hierarchy<Parent>>().track();
+        let full = world.read_resource::<Hierarchy<Parent>>().memory_usage();
+        assert!(full > empty);
+
+        let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+        hierarchy.clear();
+        assert!(hierarchy.all().is_empty());
+        assert!(hierarchy.roots().is_empty());
+        assert_eq!(hierarchy.parent(entities[1]), None);
+        assert_eq!(hierarchy.changed().read(&mut reader_id).count(), 1000);
+        hierarchy.shrink_to_fit();
+        assert!(hierarchy.memory_usage() < full);
+    }
+
+    #[test]
+    fn test_sibling_pairs() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let _e4 = world.create_entity().with(Parent { entity: e1 }).build();
+        system.run_now(&world);
+        world.maintain();
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(
+            hierarchy.sibling_pairs(),
+            vec![(e1, e2), (e1, e3), (e2, e3)]
+        );
+    }
+
+    #[test]
+    fn test_path_to_root() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+
+        let root = world.create_entity().build();
+        let mut chain = vec![root];
+        for _ in 0..5 {
+            let parent = *chain.last().unwrap();
+            chain.push(
+                world
+                    .create_entity()
+                    .with(Parent { entity: parent })
+                    .build(),
+            );
+        }
+        system.run_now(&world);
+        world.maintain();
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        let mut expected = chain.clone();
+        expected.reverse();
+        assert_eq!(hierarchy.path_to_root(chain[5]), expected);
+        assert_eq!(hierarchy.path_to_root(chain[1]), vec![chain[1], root]);
+        assert_eq!(hierarchy.path_to_root(root), vec![root]);
+    }
+
+    #[test]
+    fn test_reparent() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+
+        // e0 -> e1 -> e2, e0 -> e3
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        system.run_now(&world);
+        world.maintain();
+
+        {
+            let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+            let mut parents = world.write_storage::<Parent>();
+            let make = |entity| Parent { entity };
+            assert_eq!(
+                reparent(e1, e1, &mut parents, &hierarchy, make),
+                Err(ReparentError::WouldCycle)
+            );
+            assert_eq!(
+                reparent(e1, e2, &mut parents, &hierarchy, make),
+                Err(ReparentError::WouldCycle)
+            );
+            assert_eq!(parents.get(e1).unwrap().entity, e0);
+            assert_eq!(reparent(e1, e3, &mut parents, &hierarchy, make), Ok(()));
+        }
+        system.run_now(&world);
+        world.maintain();
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.parent(e1), Some(e3));
+        assert_eq!(hierarchy.path_to_root(e2), vec![e2, e1, e3, e0]);
+    }
+
+    #[test]
+    fn test_is_single_tree() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        system.run_now(&world);
+        assert!(!world.read_resource::<Hierarchy<Parent>>().is_single_tree());
 
-/// System for maintaining a `Hierarchy` resource.
-///
-/// ## Type parameters:
-///
-/// - `P`: Component type that provides `Parent` links for the maintained `Hierarchy`
-pub struct HierarchySystem<P> {
-    m: PhantomData<P>,
-}
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let _e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        system.run_now(&world);
+        world.maintain();
+        assert!(world.read_resource::<Hierarchy<Parent>>().is_single_tree());
 
-impl<P> HierarchySystem<P>
-where
-    P: Component + Parent + Send + Sync + 'static,
-    P::Storage: Tracked,
-{
-    pub fn new(mut world: &mut World) -> Self {
-        <Self as System<'_>>::SystemData::setup(&mut world);
-        if !world.has_value::<Hierarchy<P>>() {
-            let hierarchy = {
-                let mut storage: WriteStorage<P> = SystemData::fetch(&world);
-                Hierarchy::<P>::new(storage.register_reader())
-            };
-            world.insert(hierarchy);
+        let e3 = world.create_entity().build();
+        let _e4 = world.create_entity().with(Parent { entity: e3 }).build();
+        system.run_now(&world);
+        world.maintain();
+        assert!(!world.read_resource::<Hierarchy<Parent>>().is_single_tree());
+    }
+
+    #[test]
+    fn test_sort_index_chain() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+
+        // insert the chain bottom up, so the sorted order differs from the entity ids
+        let e0 = world.create_entity().build();
+        let e3 = world.create_entity().build();
+        let e2 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let untracked = world.create_entity().build();
+        {
+            let mut parents = world.write_storage::<Parent>();
+            parents.insert(e3, Parent { entity: e2 }).unwrap();
+            parents.insert(e2, Parent { entity: e1 }).unwrap();
         }
-        HierarchySystem { m: PhantomData }
+        system.run_now(&world);
+        world.maintain();
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        let indices = [e1, e2, e3]
+            .iter()
+            .map(|e| hierarchy.sort_index(*e).unwrap())
+            .collect::<Vec<_>>();
+        assert!(indices.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(hierarchy.sort_index(untracked), None);
+        assert_eq!(hierarchy.sort_index(e2), hierarchy.sort_index(e2));
     }
-}
 
-impl<'a, P> System<'a> for HierarchySystem<P>
-where
-    P: Component + Parent + Send + Sync + 'static,
-    P::Storage: Tracked,
-{
-    type SystemData = (ParentData<'a, P>, WriteExpect<'a, Hierarchy<P>>);
+    #[test]
+    fn test_descendants_with_path() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
 
-    fn run(&mut self, (data, mut hierarchy): Self::SystemData) {
-        hierarchy.maintain(data);
+        // e0 -> e1 -> e2 -> e3, e1 -> e4, e0 -> e5
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e0 }).build();
+        system.run_now(&world);
+        world.maintain();
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        let paths = hierarchy
+            .descendants_with_path(e0)
+            .collect::<HashMap<_, _>>();
+        assert_eq!(paths.len(), 5);
+        assert_eq!(paths[&e3], vec![e0, e1, e2, e3]);
+        assert_eq!(paths[&e4], vec![e0, e1, e4]);
+        assert_eq!(paths[&e5], vec![e0, e5]);
+        assert_eq!(
+            hierarchy.descendants_with_path(e1).last(),
+            Some((e4, vec![e1, e4]))
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
+    #[test]
+    fn test_swap_parent_and_child() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
 
-    use super::{Hierarchy, HierarchyEvent, HierarchySystem, Parent as PParent};
-    use specs::prelude::{
-        Builder, Component, DenseVecStorage, Entity, FlaggedStorage, ReaderId, RunNow, World,
-    };
-    use specs::WorldExt;
+        // root -> b -> a, with `b` created first, so its new link is handled first below
+        let root = world.create_entity().build();
+        let b = world.create_entity().with(Parent { entity: root }).build();
+        let a = world.create_entity().with(Parent { entity: b }).build();
+        system.run_now(&world);
+        world.maintain();
 
-    struct Parent {
-        entity: Entity,
+        // swap them in one frame: root -> a -> b
+        world.write_storage::<Parent>().get_mut(a).unwrap().entity = root;
+        world.write_storage::<Parent>().get_mut(b).unwrap().entity = a;
+        let result = world
+            .write_resource::<Hierarchy<Parent>>()
+            .try_maintain(world.system_data());
+        assert_eq!(result, Ok(()));
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.parent(a), Some(root));
+        assert_eq!(hierarchy.parent(b), Some(a));
+        assert_eq!(hierarchy.all(), &[a, b]);
+        assert_eq!(hierarchy.depth(b), Some(1));
     }
 
-    impl Component for Parent {
-        type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+    #[test]
+    fn test_maintain_rotation_does_not_panic() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+
+        // root -> c -> b -> a, rotated to root -> a -> c -> b in one frame
+        let root = world.create_entity().build();
+        let c = world.create_entity().with(Parent { entity: root }).build();
+        let b = world.create_entity().with(Parent { entity: c }).build();
+        let a = world.create_entity().with(Parent { entity: b }).build();
+        system.run_now(&world);
+        world.maintain();
+
+        world.write_storage::<Parent>().get_mut(c).unwrap().entity = a;
+        world.write_storage::<Parent>().get_mut(b).unwrap().entity = c;
+        world.write_storage::<Parent>().get_mut(a).unwrap().entity = root;
+        system.run_now(&world);
+        world.maintain();
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.all(), &[a, c, b]);
+        assert_eq!(hierarchy.path_to_root(b), vec![b, c, a, root]);
     }
 
-    impl PParent for Parent {
-        fn parent_entity(&self) -> Entity {
-            self.entity
-        }
+    #[test]
+    fn test_idle_maintain_skips_move_tracking() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        system.run_now(&world);
+        world.maintain();
+        // the previous order is only copied when the hierarchy changes
+        assert!(world
+            .read_resource::<Hierarchy<Parent>>()
+            .sorted_before
+            .is_empty());
+
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert!(hierarchy.sorted_before.is_empty());
+        assert_eq!(hierarchy.all(), &[e1]);
+        assert!(hierarchy.moved_in_sorted().is_empty());
     }
 
-    fn delete_removals(world: &mut World, reader_id: &mut ReaderId<HierarchyEvent>) {
-        let mut remove = vec![];
-        for event in world.fetch::<Hierarchy<Parent>>().changed().read(reader_id) {
-            if let HierarchyEvent::Removed(entity) = *event {
-                remove.push(entity);
-            }
-        }
-        for entity in remove {
-            if let Err(_) = world.delete_entity(entity) {
-                println!("Failed removed entity");
-            }
+    #[test]
+    fn test_sorted_index_after_move_below_later_sibling() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+
+        // e0 -> (e1, e2, e3), e3 -> e4, then e1 moves below e4
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let _e2 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+        system.run_now(&world);
+        world.maintain();
+
+        world.write_storage::<Parent>().get_mut(e1).unwrap().entity = e4;
+        system.run_now(&world);
+        world.maintain();
+
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        for (index, entity) in hierarchy.all().iter().enumerate() {
+            assert_eq!(hierarchy.sort_index(*entity), Some(index));
         }
+        assert!(hierarchy.is_topologically_sorted());
+        assert!(hierarchy.sort_index(e4) < hierarchy.sort_index(e1));
     }
 
     #[test]
-    fn parent_removed() {
+    fn test_insert_parent_on_root_keeps_parents_first() {
         let mut world = World::new();
-        world.register::<Parent>();
         let mut system = HierarchySystem::<Parent>::new(&mut world);
-        let mut reader_id = world.write_resource::<Hierarchy<Parent>>().track();
 
+        // two trees, e0 -> e2 -> e4 and e1 -> e3 -> e5, then e1 is inserted below e4, which is
+        // sorted after e1's children
+        let e0 = world.create_entity().build();
         let e1 = world.create_entity().build();
+        let e2 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e3 }).build();
+        system.run_now(&world);
+        world.maintain();
 
-        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        world
+            .write_storage::<Parent>()
+            .insert(e1, Parent { entity: e4 })
+            .unwrap();
+        system.run_now(&world);
+        world.maintain();
 
-        let e3 = world.create_entity().build();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert!(hierarchy.is_topologically_sorted());
+        assert_eq!(hierarchy.all(), &[e2, e4, e1, e3, e5]);
+        assert_eq!(hierarchy.path_to_root(e5), vec![e5, e3, e1, e4, e2, e0]);
+    }
 
-        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+    #[test]
+    fn test_reparent_below_chain_sorted_after_entity() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
 
-        let e5 = world.create_entity().with(Parent { entity: e4 }).build();
+        // sorted as [g, e, p2, p1], with r -> g -> p2 -> p1 and r -> e
+        let r = world.create_entity().build();
+        let g = world.create_entity().with(Parent { entity: r }).build();
+        let e = world.create_entity().with(Parent { entity: r }).build();
+        let p2 = world.create_entity().with(Parent { entity: g }).build();
+        let p1 = world.create_entity().with(Parent { entity: p2 }).build();
+        system.run_now(&world);
+        world.maintain();
+        assert_eq!(
+            world.read_resource::<Hierarchy<Parent>>().all(),
+            &[g, e, p2, p1]
+        );
 
-        system.run_now(&mut world);
-        delete_removals(&mut world, &mut reader_id);
+        // p1 and p2 move before e, while g, which is already before it, stays
+        world.write_storage::<Parent>().get_mut(e).unwrap().entity = p1;
+        system.run_now(&world);
         world.maintain();
 
-        let _ = world.delete_entity(e1);
-        system.run_now(&mut world);
-        delete_removals(&mut world, &mut reader_id);
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.all(), &[g, p2, p1, e]);
+        assert!(hierarchy.is_topologically_sorted());
+    }
+
+    #[test]
+    fn test_descendant_count_root_interior_leaf() {
+        let mut world = World::new();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+
+        // e0 -> e1 -> (e2, e3), e3 -> e4, e0 -> e5
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e3 }).build();
+        let e5 = world.create_entity().with(Parent { entity: e0 }).build();
+        system.run_now(&world);
         world.maintain();
 
-        assert_eq!(world.is_alive(e1), false);
-        assert_eq!(world.is_alive(e2), false);
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert_eq!(hierarchy.descendant_count(e0), 5);
+        assert_eq!(hierarchy.descendant_count(e1), 3);
+        assert_eq!(hierarchy.descendant_count(e3), 1);
+        assert_eq!(hierarchy.descendant_count(e2), 0);
+        assert_eq!(hierarchy.descendant_count(e4), 0);
+        assert_eq!(hierarchy.descendant_count(e5), 0);
+    }
 
-        let _ = world.delete_entity(e3);
-        system.run_now(&mut world);
-        delete_removals(&mut world, &mut reader_id);
+    #[test]
+    fn test_multi_hierarchy_swap_parent_and_child() {
+        let mut world = World::new();
+        let mut system = MultiHierarchySystem::<Parents>::new(&mut world);
+
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(parents(&[e0])).build();
+        let e2 = world.create_entity().with(parents(&[e1])).build();
+        system.run_now(&world);
         world.maintain();
 
-        assert_eq!(world.is_alive(e3), false);
-        assert_eq!(world.is_alive(e4), false);
-        assert_eq!(world.is_alive(e5), false);
+        // each link is only a cycle while the other one is not applied yet
+        world
+            .write_storage::<Parents>()
+            .insert(e1, parents(&[e2]))
+            .unwrap();
+        world
+            .write_storage::<Parents>()
+            .insert(e2, parents(&[e0]))
+            .unwrap();
+        let result = world
+            .write_resource::<MultiHierarchy<Parents>>()
+            .try_maintain(world.system_data());
+        assert_eq!(result, Ok(()));
 
-        assert_eq!(0, world.read_resource::<Hierarchy<Parent>>().all().len());
+        let hierarchy = world.read_resource::<MultiHierarchy<Parents>>();
+        assert_eq!(hierarchy.all(), &[e2, e1]);
+        assert_eq!(hierarchy.parents(e1), &[e2]);
+        assert_eq!(hierarchy.children(e2), &[e1]);
+        assert!(hierarchy.children(e1).is_empty());
     }
 
     #[test]
-    fn test_all_children_iter() {
+    fn test_multi_hierarchy_deep_chain() {
         let mut world = World::new();
-        world.register::<Parent>();
-        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let mut system = MultiHierarchySystem::<Parents>::new(&mut world);
+
+        // every entity is the child of the next one, so the whole list has to be reordered
+        let entities = (0..100_000)
+            .map(|_| world.create_entity().build())
+            .collect::<Vec<_>>();
+        {
+            let mut storage = world.write_storage::<Parents>();
+            for pair in entities.windows(2) {
+                storage.insert(pair[0], parents(&[pair[1]])).unwrap();
+            }
+        }
+        system.run_now(&world);
+
+        let hierarchy = world.read_resource::<MultiHierarchy<Parents>>();
+        let expected = entities[..entities.len() - 1]
+            .iter()
+            .rev()
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(hierarchy.all(), &expected[..]);
+    }
+
+    #[test]
+    fn test_multi_hierarchy_keeps_order_of_unrelated_entities() {
+        let mut world = World::new();
+        let mut system = MultiHierarchySystem::<Parents>::new(&mut world);
+
         let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(parents(&[e0])).build();
+        let e2 = world.create_entity().with(parents(&[e0])).build();
+        let e3 = world.create_entity().with(parents(&[e0])).build();
+        let e4 = world.create_entity().build();
+        system.run_now(&world);
+        world.maintain();
 
-        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        // only e2 and e4 need to move
+        world
+            .write_storage::<Parents>()
+            .insert(e4, parents(&[e3]))
+            .unwrap();
+        world
+            .write_storage::<Parents>()
+            .insert(e2, parents(&[e4]))
+            .unwrap();
+        system.run_now(&world);
 
-        let e2 = world.create_entity().build();
+        let hierarchy = world.read_resource::<MultiHierarchy<Parents>>();
+        assert_eq!(hierarchy.all(), &[e1, e3, e4, e2]);
+    }
 
-        let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+    #[test]
+    fn test_multi_hierarchy_no_events_without_reader() {
+        let mut world = World::new();
+        let mut system = MultiHierarchySystem::<Parents>::new(&mut world);
 
-        let e4 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(parents(&[e0])).build();
+        system.run_now(&world);
+        world.maintain();
+        world.delete_entity(e1).unwrap();
+        system.run_now(&world);
+        world.maintain();
 
-        let e5 = world.create_entity().with(Parent { entity: e3 }).build();
+        // events are only written again once a reader is registered
+        let mut reader_id = world.write_resource::<MultiHierarchy<Parents>>().track();
+        world.create_entity().with(parents(&[e0])).build();
+        system.run_now(&world);
+        let hierarchy = world.read_resource::<MultiHierarchy<Parents>>();
+        assert_eq!(hierarchy.changed().read(&mut reader_id).count(), 1);
+    }
+
+    #[test]
+    fn test_cycle_broken_next_frame() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let rejected = Arc::new(Mutex::new(Vec::new()));
+        {
+            let rejected = rejected.clone();
+            world
+                .write_resource::<Hierarchy<Parent>>()
+                .set_cycle_handler(move |child, parent| {
+                    rejected.lock().unwrap().push((child, parent))
+                });
+        }
+        let b = world.create_entity().build();
+        let a = world.create_entity().with(Parent { entity: b }).build();
+        let c = world.create_entity().build();
+        system.run_now(&world);
+        world.maintain();
+
+        world
+            .write_storage::<Parent>()
+            .insert(b, Parent { entity: a })
+            .unwrap();
+        system.run_now(&world);
+        world.maintain();
+        assert_eq!(world.read_resource::<Hierarchy<Parent>>().parent(b), None);
+
+        // the handler is not called again while the cycle stays
+        system.run_now(&world);
+        world.maintain();
+        assert_eq!(*rejected.lock().unwrap(), vec![(b, a)]);
 
-        system.run_now(&mut world);
+        // moving a away breaks the cycle, so b joins the hierarchy below a
+        world.write_storage::<Parent>().get_mut(a).unwrap().entity = c;
+        system.run_now(&world);
         world.maintain();
         let hierarchy = world.read_resource::<Hierarchy<Parent>>();
-        assert!(hierarchy.all_children_iter(e0).eq([e1].iter().cloned()));
-        assert_eq!(hierarchy.all_children_iter(e1).next(), None);
-        assert!(hierarchy
-            .all_children_iter(e2)
-            .eq([e3, e4, e5].iter().cloned()));
-        assert!(hierarchy.all_children_iter(e3).eq([e5].iter().cloned()));
-        assert_eq!(hierarchy.all_children_iter(e4).next(), None);
-        assert_eq!(hierarchy.all_children_iter(e5).next(), None);
+        hierarchy.assert_structure(&[(c, None), (a, Some(c)), (b, Some(a))]);
+        assert_eq!(hierarchy.all(), &[a, b]);
+        assert_eq!(*rejected.lock().unwrap(), vec![(b, a)]);
     }
 
     #[test]
-    fn test_all_children() {
+    fn test_clear_resets_pending_state() {
         let mut world = World::new();
         world.register::<Parent>();
-        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let _system = HierarchySystem::<Parent>::new(&mut world);
         let e0 = world.create_entity().build();
-
         let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
+        let e3 = world.create_entity().with(Parent { entity: e0 }).build();
+        world
+            .write_resource::<Hierarchy<Parent>>()
+            .maintain_accepting(world.system_data(), |_, _| true);
+        world.maintain();
 
-        let e2 = world.create_entity().build();
+        // reject e3 and move e2, then detach e1 before clearing
+        world.write_storage::<Parent>().get_mut(e2).unwrap().entity = e3;
+        world
+            .write_resource::<Hierarchy<Parent>>()
+            .maintain_accepting(world.system_data(), |child, _| child != e3);
+        world.maintain();
+        {
+            let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+            assert_eq!(hierarchy.parent(e3), None);
+            let mut parents = world.write_storage::<Parent>();
+            hierarchy.detach(e1, &mut parents);
+            hierarchy.clear();
+            assert!(hierarchy.moved_in_sorted().is_empty());
+            assert!(hierarchy.depth_changed_entities().is_empty());
+            assert_eq!(hierarchy.take_frame_delta(), FrameDelta::default());
+        }
+
+        // the rejected e3 must not be added back once it is accepted
+        world
+            .write_resource::<Hierarchy<Parent>>()
+            .maintain_accepting(world.system_data(), |_, _| true);
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        assert!(hierarchy.all().is_empty());
+        assert!(hierarchy.moved_in_sorted().is_empty());
+    }
 
+    #[test]
+    fn test_detach_and_reinsert() {
+        let mut world = World::new();
+        world.register::<Parent>();
+        let mut system = HierarchySystem::<Parent>::new(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(Parent { entity: e0 }).build();
+        let e2 = world.create_entity().with(Parent { entity: e1 }).build();
         let e3 = world.create_entity().with(Parent { entity: e2 }).build();
+        let e4 = world.create_entity().with(Parent { entity: e0 }).build();
+        system.run_now(&world);
+        world.maintain();
 
-        let e4 = world.create_entity().with(Parent { entity: e2 }).build();
+        // detaching and inserting a new component in the same frame moves the whole subtree
+        {
+            let mut parents = world.write_storage::<Parent>();
+            world
+                .write_resource::<Hierarchy<Parent>>()
+                .detach(e1, &mut parents);
+            parents.insert(e1, Parent { entity: e4 }).unwrap();
+        }
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
+        hierarchy.assert_structure(&[
+            (e0, None),
+            (e4, Some(e0)),
+            (e1, Some(e4)),
+            (e2, Some(e1)),
+            (e3, Some(e2)),
+        ]);
+        assert_eq!(hierarchy.all(), &[e4, e1, e2, e3]);
+    }
 
-        let e5 = world.create_entity().with(Parent { entity: e3 }).build();
+    #[test]
+    fn test_multi_hierarchy_parent_removed() {
+        let mut world = World::new();
+        let mut system = MultiHierarchySystem::<Parents>::new(&mut world);
 
-        system.run_now(&mut world);
+        let e0 = world.create_entity().build();
+        let e1 = world.create_entity().with(parents(&[e0])).build();
+        let e2 = world.create_entity().with(parents(&[e0, e1])).build();
+        let e3 = world.create_entity().with(parents(&[e2])).build();
+        system.run_now(&world);
         world.maintain();
-        let hierarchy = world.read_resource::<Hierarchy<Parent>>();
-        use hibitset::BitSetLike;
+        let mut reader_id = world.write_resource::<MultiHierarchy<Parents>>().track();
 
-        assert!(hierarchy
-            .all_children(e0)
-            .iter()
-            .eq([e1].iter().map(|e| e.id())));
-        assert_eq!(hierarchy.all_children(e1).iter().next(), None);
-        assert!(hierarchy
-            .all_children(e2)
-            .iter()
-            .eq([e3, e4, e5].iter().map(|e| e.id())));
-        assert!(hierarchy
-            .all_children(e3)
-            .iter()
-            .eq([e5].iter().map(|e| e.id())));
-        assert_eq!(hierarchy.all_children(e4).iter().next(), None);
-        assert_eq!(hierarchy.all_children(e5).iter().next(), None);
+        // e2 keeps e0, so it stays in the hierarchy with its child
+        world.write_storage::<Parents>().remove(e1);
+        system.run_now(&world);
+        world.maintain();
+        let hierarchy = world.read_resource::<MultiHierarchy<Parents>>();
+        assert_eq!(hierarchy.all(), &[e2, e3]);
+        assert_eq!(hierarchy.parents(e2), &[e0]);
+        assert!(hierarchy.children(e1).is_empty());
+        assert_eq!(
+            hierarchy.changed().read(&mut reader_id).collect::<Vec<_>>(),
+            vec![
+                &HierarchyEvent::Removed(e1),
+                &HierarchyEvent::Modified(e2),
+                &HierarchyEvent::Modified(e3)
+            ]
+        );
     }
 }
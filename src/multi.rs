@@ -0,0 +1,533 @@
+//! Hierarchy where entities can have several parents, see `MultiHierarchy`.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::marker::PhantomData;
+
+use hibitset::BitSetLike;
+use shrev::EventChannel;
+use specs::prelude::{
+    BitSet, Component, ComponentEvent, Entities, Entity, Join, ReadStorage, ReaderId, ResourceId,
+    System, SystemData, Tracked, World, WriteExpect, WriteStorage,
+};
+
+use entity_map::IndexMap;
+use {read_component_events, write_event, HierarchyError, HierarchyEvent};
+
+/// Bound for the parent component of a `MultiHierarchy`, which can link to several parents.
+///
+/// Like `Parent`, the component should indicate that the `Entity` it is added to *has* these
+/// parents.
+pub trait MultiParent {
+    /// Retrieves the parent entities. Duplicates are ignored.
+    fn parent_entities(&self) -> &[Entity];
+}
+
+/// Directed acyclic graph of entities, where each entity can have several parents.
+///
+/// This is the counterpart of `Hierarchy` for `MultiParent` components. The entities are kept
+/// in a sorted order where all parents of an entity are guaranteed to be before it, and the same
+/// events are sent on the internal `EventChannel`:
+///
+/// - `Modified` when the parents of an entity change, including when one of its parents leaves
+///   the hierarchy, which is also sent for all of its recursive children.
+/// - `Removed` when an entity leaves the hierarchy. An entity leaves when its `MultiParent`
+///   component is removed or lists no valid parents, or when all of its parents left. Its
+///   children are only removed with it if they have no other parent left.
+/// - `Invalid` when an entity lists itself as a parent, which is ignored.
+///
+/// Links that would create a cycle are always ignored. `try_maintain` reports them as an error,
+/// while `maintain` panics on them.
+pub struct MultiHierarchy<P> {
+    sorted: Vec<Entity>,
+    entities: IndexMap<usize>,
+    parents: HashMap<Entity, Vec<Entity>>,
+    children: HashMap<Entity, Vec<Entity>>,
+    changed: EventChannel<HierarchyEvent>,
+
+    reader_id: ReaderId<ComponentEvent>,
+    modified: BitSet,
+    inserted: BitSet,
+    removed: BitSet,
+    cycles: Vec<Vec<Entity>>,
+    deferred: Vec<(Entity, Entity)>,
+
+    _phantom: PhantomData<P>,
+}
+
+impl<P> MultiHierarchy<P> {
+    /// Create a new hierarchy object.
+    pub fn new(reader_id: ReaderId<ComponentEvent>) -> Self
+    where
+        P: Component,
+        P::Storage: Tracked,
+    {
+        MultiHierarchy {
+            sorted: Vec::new(),
+            entities: IndexMap::new(),
+            parents: HashMap::new(),
+            children: HashMap::new(),
+            changed: EventChannel::new(),
+
+            reader_id,
+            modified: BitSet::new(),
+            inserted: BitSet::new(),
+            removed: BitSet::new(),
+            cycles: Vec::new(),
+            deferred: Vec::new(),
+
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Get all entities that have parents, in sorted order, where all parents of an entity are
+    /// guaranteed to be before it.
+    ///
+    /// Note: This does not include entities that are only parents.
+    pub fn all(&self) -> &[Entity] {
+        self.sorted.as_slice()
+    }
+
+    /// Get the index of this entity in the sorted order, see `all`.
+    pub fn sort_index(&self, entity: Entity) -> Option<usize> {
+        self.entities
+            .get(&entity.id())
+            .cloned()
+            .filter(|index| self.sorted[*index] == entity)
+    }
+
+    /// Get the parents of a specific entity, in the order of its `MultiParent` component.
+    pub fn parents(&self, entity: Entity) -> &[Entity] {
+        self.parents
+            .get(&entity)
+            .map(|vec| vec.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Get the immediate children of a specific entity, in the order they were added in.
+    pub fn children(&self, entity: Entity) -> &[Entity] {
+        self.children
+            .get(&entity)
+            .map(|vec| vec.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Returns `true` if `ancestor` can be reached from `descendant` by following parents.
+    ///
+    /// An entity is not its own ancestor.
+    pub fn is_ancestor(&self, ancestor: Entity, descendant: Entity) -> bool {
+        self.path_to_ancestor(descendant, ancestor).is_some()
+    }
+
+    /// Get a reader for the modification events.
+    pub fn track(&mut self) -> ReaderId<HierarchyEvent> {
+        self.changed.register_reader()
+    }
+
+    /// Get the `EventChannel` for the modification events for reading.
+    pub fn changed(&self) -> &EventChannel<HierarchyEvent> {
+        &self.changed
+    }
+
+    /// Maintain the hierarchy, usually only called by `MultiHierarchySystem`.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if a parent link would create a cycle. Use `try_maintain` to handle cycles as an
+    /// error instead.
+    pub fn maintain(&mut self, data: MultiParentData<P>)
+    where
+        P: Component + MultiParent,
+        P::Storage: Tracked,
+    {
+        if let Err(err) = self.try_maintain(data) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Maintain the hierarchy, reporting parent links that would create a cycle as an error.
+    ///
+    /// The offending links are ignored, and the rest of the changes are still applied, so the
+    /// hierarchy stays consistent. If there are several cycles, only the first one is reported.
+    /// Changes are checked together, so links that are only cyclic halfway through applying them
+    /// are not reported.
+    pub fn try_maintain(&mut self, data: MultiParentData<P>) -> Result<(), HierarchyError>
+    where
+        P: Component + MultiParent,
+        P::Storage: Tracked,
+    {
+        let MultiParentData { entities, parents } = data;
+
+        self.cycles.clear();
+        self.modified.clear();
+        self.inserted.clear();
+        self.removed.clear();
+
+        read_component_events(
+            parents.channel().read(&mut self.reader_id),
+            &mut self.modified,
+            &mut self.inserted,
+            &mut self.removed,
+        );
+
+        // remove entities that lost their component, and parents that were deleted
+        let mut removed = Vec::new();
+        for id in (&self.removed).iter() {
+            if let Some(index) = self.entities.get(&id) {
+                removed.push(self.sorted[*index]);
+            }
+        }
+        for parent in self.children.keys() {
+            if !self.parents.contains_key(parent) && !entities.is_alive(*parent) {
+                removed.push(*parent);
+            }
+        }
+        let unlinked = self.remove(removed);
+
+        // apply new and changed parent links, children that only lost some of their parents
+        // count as changed as well
+        let mut updated = unlinked;
+        let mut is_updated = updated.iter().cloned().collect::<HashSet<_>>();
+        let mut relinked = BitSet::new();
+        relinked |= &self.inserted;
+        relinked |= &self.modified;
+        for (entity, _, parent) in (&*entities, &relinked, &parents).join() {
+            for parent_entity in parent.parent_entities() {
+                if *parent_entity == entity {
+                    self.emit(HierarchyEvent::Invalid(entity));
+                }
+            }
+            if self.relink(entity, parent.parent_entities()) && is_updated.insert(entity) {
+                updated.push(entity);
+            }
+        }
+
+        // links that looked like cycles can become valid once the other links of this frame are
+        // applied, so retry them until nothing changes
+        let mut pending = std::mem::take(&mut self.deferred);
+        while !pending.is_empty() {
+            let count = pending.len();
+            let mut retried = pending
+                .drain(..)
+                .map(|(entity, _)| entity)
+                .collect::<Vec<_>>();
+            retried.dedup();
+            for entity in retried {
+                if let Some(parent) = parents.get(entity) {
+                    if self.relink(entity, parent.parent_entities()) && is_updated.insert(entity) {
+                        updated.push(entity);
+                    }
+                }
+            }
+            std::mem::swap(&mut pending, &mut self.deferred);
+            if pending.len() == count {
+                break;
+            }
+        }
+        // whatever is left forms a cycle in the final parent links
+        for (entity, parent_entity) in pending.drain(..) {
+            if let Some(mut cycle) = self.path_to_ancestor(parent_entity, entity) {
+                cycle.insert(0, entity);
+                self.cycles.push(cycle);
+            }
+        }
+        self.deferred = pending;
+
+        // entities without any valid parent are roots, which are not part of the sorted list,
+        // but keep their children
+        let mut index = 0;
+        while index < updated.len() {
+            let entity = updated[index];
+            if self.parents(entity).is_empty() {
+                updated.remove(index);
+                self.parents.remove(&entity);
+                if self.sort_index(entity).is_some() {
+                    self.emit(HierarchyEvent::Removed(entity));
+                }
+            } else {
+                index += 1;
+            }
+        }
+
+        self.update_sorted(&updated);
+
+        // notify the changed entities and all of their recursive children
+        if !updated.is_empty() {
+            let mut notify = updated.into_iter().collect::<HashSet<_>>();
+            for i in 0..self.sorted.len() {
+                let entity = self.sorted[i];
+                if notify.contains(&entity)
+                    || self.parents(entity).iter().any(|p| notify.contains(p))
+                {
+                    notify.insert(entity);
+                    self.emit(HierarchyEvent::Modified(entity));
+                }
+            }
+        }
+
+        match self.cycles.drain(..).next() {
+            Some(cycle) => Err(HierarchyError::Cycle(cycle)),
+            None => Ok(()),
+        }
+    }
+
+    fn emit(&mut self, event: HierarchyEvent) {
+        write_event(&mut self.changed, event);
+    }
+
+    /// Replace the parents of `entity` with `new_parents`, deferring links that would create a
+    /// cycle. Returns `true` if the parents changed.
+    fn relink(&mut self, entity: Entity, new_parents: &[Entity]) -> bool {
+        let old_parents = self.parents.remove(&entity).unwrap_or_default();
+        for parent in &old_parents {
+            if !new_parents.contains(parent) {
+                self.unlink_child(*parent, entity);
+            }
+        }
+        let mut parents = Vec::with_capacity(new_parents.len());
+        for parent in new_parents {
+            if *parent == entity || parents.contains(parent) {
+                continue;
+            }
+            if old_parents.contains(parent) {
+                parents.push(*parent);
+                continue;
+            }
+            // only an entity with children can be part of a cycle
+            if self.children.contains_key(&entity)
+                && self.path_to_ancestor(*parent, entity).is_some()
+            {
+                // retried after the other links of the frame, see `try_maintain`
+                self.deferred.push((entity, *parent));
+                continue;
+            }
+            self.children.entry(*parent).or_default().push(entity);
+            parents.push(*parent);
+        }
+        let changed = parents != old_parents;
+        self.parents.insert(entity, parents);
+        changed
+    }
+
+    fn unlink_child(&mut self, parent: Entity, child: Entity) {
+        let empty = match self.children.get_mut(&parent) {
+            Some(children) => {
+                children.retain(|e| *e != child);
+                children.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.children.remove(&parent);
+        }
+    }
+
+    /// Find a path from `entity` up to `ancestor` by following parents, as the list of entities
+    /// on the way, starting with `entity` and without `ancestor`.
+    fn path_to_ancestor(&self, entity: Entity, ancestor: Entity) -> Option<Vec<Entity>> {
+        let mut previous = HashMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(entity);
+        while let Some(current) = queue.pop_front() {
+            for parent in self.parents(current) {
+                if *parent == ancestor {
+                    let mut path = vec![current];
+                    let mut step = current;
+                    while let Some(next) = previous.get(&step) {
+                        path.push(*next);
+                        step = *next;
+                    }
+                    path.reverse();
+                    return Some(path);
+                }
+                if *parent != entity && !previous.contains_key(parent) {
+                    previous.insert(*parent, current);
+                    queue.push_back(*parent);
+                }
+            }
+        }
+        None
+    }
+
+    /// Remove `entities` from the hierarchy, together with the children that lose all of their
+    /// parents because of it.
+    ///
+    /// The removed entities stay in the sorted list until `update_sorted`. Returns the children
+    /// that lost some, but not all, of their parents.
+    fn remove(&mut self, entities: Vec<Entity>) -> Vec<Entity> {
+        let mut queue = VecDeque::from(entities);
+        let mut removed = HashSet::new();
+        let mut unlinked = Vec::new();
+        let mut is_unlinked = HashSet::new();
+        while let Some(entity) = queue.pop_front() {
+            if !removed.insert(entity) {
+                continue;
+            }
+            let parents = self.parents.remove(&entity);
+            let linked = parents.is_some();
+            for parent in parents.unwrap_or_default() {
+                self.unlink_child(parent, entity);
+            }
+            for child in self.children.remove(&entity).unwrap_or_default() {
+                let orphaned = match self.parents.get_mut(&child) {
+                    Some(parents) => {
+                        parents.retain(|e| *e != entity);
+                        parents.is_empty()
+                    }
+                    None => continue,
+                };
+                if orphaned {
+                    queue.push_back(child);
+                } else if is_unlinked.insert(child) {
+                    unlinked.push(child);
+                }
+            }
+            if linked {
+                self.emit(HierarchyEvent::Removed(entity));
+            }
+        }
+        // a child can lose its last parent after losing the first ones
+        let parents = &self.parents;
+        unlinked.retain(|child| parents.contains_key(child));
+        unlinked
+    }
+
+    /// Bring the sorted list up to date after entities left the hierarchy, and the entities in
+    /// `updated` got new parents.
+    ///
+    /// This keeps the previous order, appends new entities, and only reorders the range between
+    /// an entity and the latest of its parents that ended up after it.
+    fn update_sorted(&mut self, updated: &[Entity]) {
+        // drop the entities that left, keeping the order of the rest
+        let mut first_changed = None;
+        {
+            let parents = &self.parents;
+            let entities = &mut self.entities;
+            let mut index = 0;
+            self.sorted.retain(|entity| {
+                let keep = parents.contains_key(entity);
+                if !keep {
+                    entities.remove(&entity.id());
+                    first_changed = first_changed.or(Some(index));
+                }
+                index += 1;
+                keep
+            });
+        }
+        if let Some(first) = first_changed {
+            for i in first..self.sorted.len() {
+                self.entities.insert(self.sorted[i].id(), i);
+            }
+        }
+        for entity in updated {
+            if self.sort_index(*entity).is_none() {
+                self.entities.insert(entity.id(), self.sorted.len());
+                self.sorted.push(*entity);
+            }
+        }
+
+        // find the range with parents after their children
+        let mut range = None;
+        for entity in updated {
+            let index = self.sort_index(*entity).unwrap();
+            for parent in self.parents(*entity) {
+                match self.sort_index(*parent) {
+                    Some(parent_index) if parent_index > index => {
+                        let (start, end) = range.unwrap_or((index, parent_index));
+                        range = Some((start.min(index), end.max(parent_index)));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if let Some((start, end)) = range {
+            self.sort_range(start, end);
+        }
+    }
+
+    /// Reorder the sorted list between `start` and `end`, both inclusive, so that all parents in
+    /// that range are before their children, keeping the previous order where possible.
+    fn sort_range(&mut self, start: usize, end: usize) {
+        let in_range = |hierarchy: &Self, entity: &Entity| {
+            hierarchy
+                .sort_index(*entity)
+                .map(|index| index >= start && index <= end)
+                .unwrap_or(false)
+        };
+        let mut order = Vec::with_capacity(end - start + 1);
+        let mut placed = HashSet::new();
+        // entities with whether their parents were already pushed
+        let mut stack = Vec::new();
+        for i in start..=end {
+            stack.push((self.sorted[i], false));
+            while let Some((entity, expanded)) = stack.pop() {
+                if expanded {
+                    order.push(entity);
+                    continue;
+                }
+                if !placed.insert(entity) {
+                    continue;
+                }
+                stack.push((entity, true));
+                for parent in self.parents(entity).iter().rev() {
+                    if !placed.contains(parent) && in_range(self, parent) {
+                        stack.push((*parent, false));
+                    }
+                }
+            }
+        }
+        for (offset, entity) in order.into_iter().enumerate() {
+            self.sorted[start + offset] = entity;
+            self.entities.insert(entity.id(), start + offset);
+        }
+    }
+}
+
+/// Utility struct for the data needed by the `MultiHierarchy` maintain.
+#[derive(SystemData)]
+pub struct MultiParentData<'a, P>
+where
+    P: Component + MultiParent,
+    P::Storage: Tracked,
+{
+    entities: Entities<'a>,
+    parents: ReadStorage<'a, P>,
+}
+
+/// System for maintaining a `MultiHierarchy` resource.
+///
+/// ## Type parameters:
+///
+/// - `P`: Component type that provides `MultiParent` links for the maintained `MultiHierarchy`
+pub struct MultiHierarchySystem<P> {
+    m: PhantomData<P>,
+}
+
+impl<P> MultiHierarchySystem<P>
+where
+    P: Component + MultiParent + Send + Sync + 'static,
+    P::Storage: Tracked,
+{
+    pub fn new(world: &mut World) -> Self {
+        <Self as System<'_>>::SystemData::setup(world);
+        if !world.has_value::<MultiHierarchy<P>>() {
+            let hierarchy = {
+                let mut storage: WriteStorage<P> = SystemData::fetch(world);
+                MultiHierarchy::<P>::new(storage.register_reader())
+            };
+            world.insert(hierarchy);
+        }
+        MultiHierarchySystem { m: PhantomData }
+    }
+}
+
+impl<'a, P> System<'a> for MultiHierarchySystem<P>
+where
+    P: Component + MultiParent + Send + Sync + 'static,
+    P::Storage: Tracked,
+{
+    type SystemData = (MultiParentData<'a, P>, WriteExpect<'a, MultiHierarchy<P>>);
+
+    fn run(&mut self, (data, mut hierarchy): Self::SystemData) {
+        hierarchy.maintain(data);
+    }
+}
@@ -0,0 +1,111 @@
+extern crate specs;
+extern crate specs_hierarchy;
+
+use specs::prelude::*;
+use specs_hierarchy::{Hierarchy, HierarchySystem};
+use std::alloc::{GlobalAlloc, Layout, System as SystemAlloc};
+use std::cell::Cell;
+use std::sync::Mutex;
+
+/// Allocator that counts the allocations made on each thread, to check that `maintain`
+/// reuses its buffers.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        SystemAlloc.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        SystemAlloc.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocation_count() -> usize {
+    ALLOCATIONS.with(|count| count.get())
+}
+
+/// Held by every test in this file, so the allocation counts are not affected by other tests.
+static LOCK: Mutex<()> = Mutex::new(());
+
+struct Parent {
+    entity: Entity,
+}
+
+impl Component for Parent {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+impl specs_hierarchy::Parent for Parent {
+    fn parent_entity(&self) -> Entity {
+        self.entity
+    }
+}
+
+#[test]
+fn test_maintain_reuses_buffers() {
+    let _lock = LOCK.lock().unwrap();
+    let mut world = World::new();
+    world.register::<Parent>();
+    let mut system = HierarchySystem::<Parent>::new(&mut world);
+    let roots = [world.create_entity().build(), world.create_entity().build()];
+    let children = (0..100)
+        .map(|_| {
+            world
+                .create_entity()
+                .with(Parent { entity: roots[0] })
+                .build()
+        })
+        .collect::<Vec<_>>();
+    let grandchildren = children
+        .iter()
+        .step_by(10)
+        .map(|child| {
+            world
+                .create_entity()
+                .with(Parent { entity: *child })
+                .build()
+        })
+        .collect::<Vec<_>>();
+    system.run_now(&world);
+    world.maintain();
+
+    let mut allocations = 0;
+    for frame in 0..200 {
+        {
+            let mut parents = world.write_storage::<Parent>();
+            for (i, child) in children.iter().enumerate() {
+                if i % 3 == frame % 3 {
+                    parents.get_mut(*child).unwrap().entity = roots[(frame + i) % 2];
+                }
+            }
+        }
+        let data = world.system_data();
+        let mut hierarchy = world.write_resource::<Hierarchy<Parent>>();
+        let before = allocation_count();
+        hierarchy.maintain(data);
+        // the buffers grow during the first frames, after that they are reused
+        if frame >= 10 {
+            allocations += allocation_count() - before;
+        }
+
+        let parents = world.read_storage::<Parent>();
+        for entity in children.iter().chain(&grandchildren) {
+            assert_eq!(
+                hierarchy.parent(*entity),
+                Some(parents.get(*entity).unwrap().entity)
+            );
+        }
+        assert_eq!(hierarchy.all().len(), 110);
+        assert!(hierarchy.is_topologically_sorted());
+    }
+    assert_eq!(allocations, 0);
+}